@@ -0,0 +1,391 @@
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use actix_web::rt::time::interval;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    app_config::{self, AlarmCondition, AlarmConfig, AlarmJournalConfig},
+    device::{valid_name, Device, Devices},
+    device_health::DeviceState,
+    events::{Event, EventBus},
+    journal::Journal,
+    poller::Quality,
+};
+
+/// 多久检查一次告警条件，delay_on/delay_off的精度也是这个
+const EVAL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AlarmState {
+    #[default]
+    Normal,
+    /// 条件满足，还没确认
+    Active,
+    /// 条件满足，已确认
+    Acknowledged,
+    /// 条件已经不满足，但还没确认
+    Cleared,
+}
+
+impl AlarmState {
+    fn is_active(&self) -> bool {
+        matches!(self, AlarmState::Active | AlarmState::Acknowledged)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Transition {
+    Raised,
+    Cleared,
+    Acknowledged,
+}
+
+/// 告警状态变化，发到事件流并写入告警日志
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AlarmEvent {
+    pub timestamp: DateTime<Local>,
+    pub id: String,
+    pub device: String,
+    pub alarm: String,
+    pub tag: Option<String>,
+    pub severity: String,
+    pub transition: Transition,
+    pub state: AlarmState,
+    /// 变化时数据点的值
+    pub value: Option<u16>,
+    pub message: String,
+    /// 确认告警的调用方
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity: Option<String>,
+}
+
+/// /alarms返回的告警当前状态
+#[derive(Serialize, Clone, Debug)]
+pub struct AlarmStatus {
+    pub id: String,
+    pub device: String,
+    pub alarm: String,
+    pub tag: Option<String>,
+    pub severity: String,
+    pub message: String,
+    pub state: AlarmState,
+    pub value: Option<u16>,
+    pub raised_at: Option<DateTime<Local>>,
+    pub cleared_at: Option<DateTime<Local>>,
+    pub acked_at: Option<DateTime<Local>>,
+    pub acked_by: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum AckError {
+    UnknownAlarm,
+    /// 告警没有产生，或者已经恢复并确认过
+    NotRaised,
+}
+
+impl fmt::Display for AckError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AckError::UnknownAlarm => write!(f, "告警不存在"),
+            AckError::NotRaised => write!(f, "告警不需要确认"),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Runtime {
+    state: AlarmState,
+    /// 条件开始和当前状态不一致的时间，用于delay_on/delay_off
+    pending_since: Option<Instant>,
+    value: Option<u16>,
+    /// 上一次的采样，用于计算变化率
+    last_sample: Option<(u16, DateTime<Local>)>,
+    rate: Option<f64>,
+    raised_at: Option<DateTime<Local>>,
+    cleared_at: Option<DateTime<Local>>,
+    acked_at: Option<DateTime<Local>>,
+    acked_by: Option<String>,
+}
+
+struct Alarm {
+    id: String,
+    device: Arc<Device>,
+    config: AlarmConfig,
+    /// 数据点的寄存器，unreachable告警为None
+    register: Option<u16>,
+    runtime: Mutex<Runtime>,
+}
+
+impl Alarm {
+    /// 当前条件是否满足，数据不可用时返回None，保持原来的状态
+    fn condition(&self, runtime: &Runtime) -> Option<bool> {
+        let active = runtime.state.is_active();
+        let hysteresis = if active { self.config.hysteresis } else { 0.0 };
+        let value = runtime.value.map(f64::from);
+        match &self.config.condition {
+            AlarmCondition::High { limit } => value.map(|v| v > limit - hysteresis),
+            AlarmCondition::Low { limit } => value.map(|v| v < limit + hysteresis),
+            AlarmCondition::BitSet { bit } => runtime.value.map(|v| v & (1 << bit) != 0),
+            AlarmCondition::Equals { value } => runtime.value.map(|v| v == *value),
+            AlarmCondition::RateOfChange { limit } => {
+                runtime.rate.map(|rate| rate.abs() > limit - hysteresis)
+            }
+            AlarmCondition::Unreachable => {
+                // 刚启动还没探测过时状态也是down，要真的失败过才算
+                let health = self.device.health.snapshot();
+                Some(health.state == DeviceState::Down && health.consecutive_failures > 0)
+            }
+        }
+    }
+
+    fn status(&self) -> AlarmStatus {
+        let runtime = self.runtime.lock().unwrap();
+        AlarmStatus {
+            id: self.id.clone(),
+            device: self.device.name.clone(),
+            alarm: self.config.name.clone(),
+            tag: self.config.tag.clone(),
+            severity: self.config.severity.clone(),
+            message: self.config.message.clone(),
+            state: runtime.state,
+            value: runtime.value,
+            raised_at: runtime.raised_at,
+            cleared_at: runtime.cleared_at,
+            acked_at: runtime.acked_at,
+            acked_by: runtime.acked_by.clone(),
+        }
+    }
+
+    fn event(&self, runtime: &Runtime, transition: Transition) -> AlarmEvent {
+        AlarmEvent {
+            timestamp: Local::now(),
+            id: self.id.clone(),
+            device: self.device.name.clone(),
+            alarm: self.config.name.clone(),
+            tag: self.config.tag.clone(),
+            severity: self.config.severity.clone(),
+            transition,
+            state: runtime.state,
+            value: runtime.value,
+            message: self.config.message.clone(),
+            identity: None,
+        }
+    }
+
+    /// 用缓存里的最新值更新条件，需要的话改变状态，返回产生的事件
+    fn evaluate(&self) -> Option<AlarmEvent> {
+        let mut runtime = self.runtime.lock().unwrap();
+        if let Some(register) = self.register {
+            match self.device.cache.get(register) {
+                Some(sample) if sample.quality == Quality::Good => {
+                    if runtime.last_sample.map(|(_, t)| t) != Some(sample.timestamp) {
+                        if let Some((last, t)) = runtime.last_sample {
+                            let seconds = (sample.timestamp - t).num_milliseconds() as f64 / 1000.0;
+                            if seconds > 0.0 {
+                                runtime.rate = Some((sample.value as f64 - last as f64) / seconds);
+                            }
+                        }
+                        runtime.last_sample = Some((sample.value, sample.timestamp));
+                    }
+                    runtime.value = Some(sample.value);
+                }
+                // 读不到值时不改变状态，通讯问题由unreachable告警负责
+                _ => return None,
+            }
+        }
+        let condition = self.condition(&runtime)?;
+        if condition == runtime.state.is_active() {
+            runtime.pending_since = None;
+            return None;
+        }
+        let pending_since = *runtime.pending_since.get_or_insert_with(Instant::now);
+        let delay = if condition {
+            self.config.delay_on_ms
+        } else {
+            self.config.delay_off_ms
+        };
+        if pending_since.elapsed() < Duration::from_millis(delay) {
+            return None;
+        }
+        runtime.pending_since = None;
+        let transition = if condition {
+            runtime.state = AlarmState::Active;
+            runtime.raised_at = Some(Local::now());
+            runtime.cleared_at = None;
+            runtime.acked_at = None;
+            runtime.acked_by = None;
+            Transition::Raised
+        } else {
+            runtime.state = match runtime.state {
+                AlarmState::Acknowledged => AlarmState::Normal,
+                _ => AlarmState::Cleared,
+            };
+            runtime.cleared_at = Some(Local::now());
+            Transition::Cleared
+        };
+        Some(self.event(&runtime, transition))
+    }
+}
+
+/// 告警引擎：定时用轮询缓存和设备状态检查所有告警条件
+pub struct AlarmEngine {
+    alarms: Vec<Alarm>,
+    journal: Journal<AlarmEvent>,
+    events: Arc<EventBus>,
+}
+
+impl AlarmEngine {
+    pub fn new(
+        configs: &[app_config::Modbus],
+        devices: &Devices,
+        journal: &AlarmJournalConfig,
+        events: Arc<EventBus>,
+    ) -> Result<Self, String> {
+        let mut alarms: Vec<Alarm> = Vec::new();
+        for config in configs {
+            let device = devices[&config.name].clone();
+            for alarm in &config.alarms {
+                if !valid_name(&alarm.name) {
+                    return Err(format!(
+                        "设备{}的告警名字{:?}不合法，只能包含字母、数字、_、-和.",
+                        config.name, alarm.name
+                    ));
+                }
+                let id = format!("{}.{}", config.name, alarm.name);
+                if alarms.iter().any(|other| other.id == id) {
+                    return Err(format!("告警{}重复", id));
+                }
+                if let AlarmCondition::BitSet { bit } = alarm.condition {
+                    if bit >= 16 {
+                        return Err(format!(
+                            "告警{}的bit {}超出范围，寄存器只有16位（0-15）",
+                            id, bit
+                        ));
+                    }
+                }
+                let register = match (&alarm.condition, &alarm.tag) {
+                    (AlarmCondition::Unreachable, _) => None,
+                    (_, None) => return Err(format!("告警{}没有配置tag", id)),
+                    (_, Some(tag)) => Some(
+                        device
+                            .tags
+                            .iter()
+                            .find(|t| &t.name == tag)
+                            .ok_or_else(|| format!("告警{}的数据点{}不存在", id, tag))?
+                            .register,
+                    ),
+                };
+                alarms.push(Alarm {
+                    id,
+                    device: device.clone(),
+                    config: alarm.clone(),
+                    register,
+                    runtime: Mutex::new(Runtime::default()),
+                });
+            }
+        }
+        let journal = Journal::open(
+            "告警日志",
+            &journal.directory,
+            &journal.file_name,
+            journal.memory_entries,
+        )
+        .map_err(|err| format!("打开告警日志失败：{}", err))?;
+        Ok(AlarmEngine {
+            alarms,
+            journal,
+            events,
+        })
+    }
+
+    fn emit(&self, event: AlarmEvent) {
+        match event.transition {
+            Transition::Raised => warn!(
+                "告警{}产生：{}，值{:?}",
+                event.id, event.message, event.value
+            ),
+            Transition::Cleared => info!("告警{}恢复，值{:?}", event.id, event.value),
+            Transition::Acknowledged => {
+                info!("告警{}被{:?}确认", event.id, event.identity)
+            }
+        }
+        self.journal.record(event.clone());
+        self.events.publish(Event::Alarm(event));
+    }
+
+    fn evaluate(&self) {
+        for alarm in &self.alarms {
+            if let Some(event) = alarm.evaluate() {
+                self.emit(event);
+            }
+        }
+    }
+
+    /// 除了normal以外的告警，`all`为true时包括normal
+    pub fn statuses(&self, all: bool) -> Vec<AlarmStatus> {
+        self.alarms
+            .iter()
+            .map(Alarm::status)
+            .filter(|status| all || status.state != AlarmState::Normal)
+            .collect()
+    }
+
+    /// 告警所属的设备，用于权限检查
+    pub fn device_of(&self, id: &str) -> Option<&str> {
+        self.alarms
+            .iter()
+            .find(|alarm| alarm.id == id)
+            .map(|alarm| alarm.device.name.as_str())
+    }
+
+    pub fn acknowledge(&self, id: &str, identity: &str) -> Result<AlarmStatus, AckError> {
+        let alarm = self
+            .alarms
+            .iter()
+            .find(|alarm| alarm.id == id)
+            .ok_or(AckError::UnknownAlarm)?;
+        let event = {
+            let mut runtime = alarm.runtime.lock().unwrap();
+            runtime.state = match runtime.state {
+                AlarmState::Active => AlarmState::Acknowledged,
+                AlarmState::Cleared => AlarmState::Normal,
+                // 重复确认不算错，也不再记一次
+                AlarmState::Acknowledged => {
+                    drop(runtime);
+                    return Ok(alarm.status());
+                }
+                AlarmState::Normal => return Err(AckError::NotRaised),
+            };
+            runtime.acked_at = Some(Local::now());
+            runtime.acked_by = Some(identity.to_string());
+            AlarmEvent {
+                identity: Some(identity.to_string()),
+                ..alarm.event(&runtime, Transition::Acknowledged)
+            }
+        };
+        self.emit(event);
+        Ok(alarm.status())
+    }
+
+    /// 满足条件的最近n条告警日志，最新的在前
+    pub fn journal(&self, n: usize, filter: impl Fn(&AlarmEvent) -> bool) -> Vec<AlarmEvent> {
+        self.journal.recent_matching(n, filter)
+    }
+}
+
+/// 定时检查告警条件，直到进程退出
+pub async fn alarm_loop(engine: Arc<AlarmEngine>) {
+    let mut ticker = interval(EVAL_INTERVAL);
+    loop {
+        ticker.tick().await;
+        engine.evaluate();
+    }
+}
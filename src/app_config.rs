@@ -1,25 +1,716 @@
+use std::collections::HashMap;
+
 use config::{Config, ConfigError};
-use serde::Deserialize;
+use jsonwebtoken::Algorithm;
+use serde::{Deserialize, Serialize};
 
+/// `address`是HTTP监听地址，`tls`是HTTPS，两个都配置时同时监听
 #[derive(Debug, Deserialize)]
 pub struct ServerConfig {
+    pub address: Option<String>,
+    pub tls: Option<TlsConfig>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct TlsConfig {
     pub address: String,
+    pub cert_file: String,
+    pub key_file: String,
+    /// 配置了CA时校验客户端证书（mTLS）
+    pub client_ca_file: Option<String>,
+    /// false时没有客户端证书也允许连接，有的话仍然要校验通过
+    #[serde(default = "default_true")]
+    pub client_auth_required: bool,
+    /// 多久检查一次证书文件是否变化
+    #[serde(default = "default_reload_interval_ms")]
+    pub reload_interval_ms: u64,
+}
+fn default_true() -> bool {
+    true
+}
+fn default_reload_interval_ms() -> u64 {
+    5000
 }
 #[derive(Debug, Deserialize)]
 pub struct Modbus {
     pub address: String,
     pub slave_id: u8,
     pub name: String,
+    #[serde(default)]
+    pub probe: ProbeConfig,
+    #[serde(default)]
+    pub breaker: BreakerConfig,
+    /// Modbus/TCP Security，配置后用TLS连接（一般是802端口）
+    pub tls: Option<ModbusTlsConfig>,
+    #[serde(default)]
+    pub write_policy: WritePolicyConfig,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    #[serde(default)]
+    pub poll: PollConfig,
+    /// 后台轮询的数据点，没有配置时不轮询
+    #[serde(default)]
+    pub tags: Vec<TagConfig>,
+    #[serde(default)]
+    pub alarms: Vec<AlarmConfig>,
+    /// 透明代理，让调试工具和网关共用设备唯一的连接
+    pub proxy: Option<ProxyConfig>,
+}
+
+/// 后台轮询配置，相邻的数据点合并成一次读
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct PollConfig {
+    pub interval_ms: u64,
+    /// 两个数据点之间空出不超过这么多寄存器时合并成一次读
+    pub max_gap: u16,
+}
+impl Default for PollConfig {
+    fn default() -> Self {
+        PollConfig {
+            interval_ms: 1000,
+            max_gap: 8,
+        }
+    }
+}
+
+/// 一个数据点，对应一个保持寄存器
+#[derive(Debug, Deserialize, Clone)]
+pub struct TagConfig {
+    pub name: String,
+    pub register: u16,
+    pub unit: Option<String>,
+    /// 和上一次存储的值相差超过死区才存历史，0表示值变了就存
+    #[serde(default)]
+    pub deadband: f64,
+    /// 历史保留天数，不配置时用[history]的默认值
+    pub retention_days: Option<u32>,
+    /// 是否存历史
+    #[serde(default = "default_true")]
+    pub history: bool,
+}
+
+/// 告警条件，`type`区分种类
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AlarmCondition {
+    /// 值大于limit
+    High {
+        limit: f64,
+    },
+    /// 值小于limit
+    Low {
+        limit: f64,
+    },
+    /// 第bit位（0开始）为1
+    BitSet {
+        bit: u8,
+    },
+    Equals {
+        value: u16,
+    },
+    /// 每秒变化量的绝对值大于limit
+    RateOfChange {
+        limit: f64,
+    },
+    /// 设备状态为down，不需要tag
+    Unreachable,
+}
+
+/// 一个告警，id为`设备名.name`
+#[derive(Debug, Deserialize, Clone)]
+pub struct AlarmConfig {
+    pub name: String,
+    /// 条件作用的数据点，unreachable不需要
+    pub tag: Option<String>,
+    pub condition: AlarmCondition,
+    /// 回差：high在值低于limit - hysteresis后才恢复，low、rate_of_change同理
+    #[serde(default)]
+    pub hysteresis: f64,
+    /// 条件持续满足这么久才报警
+    #[serde(default)]
+    pub delay_on_ms: u64,
+    /// 条件持续不满足这么久才恢复
+    #[serde(default)]
+    pub delay_off_ms: u64,
+    #[serde(default = "default_severity")]
+    pub severity: String,
+    #[serde(default)]
+    pub message: String,
+}
+fn default_severity() -> String {
+    "warning".to_string()
+}
+
+/// 每个设备的请求限速，请求一个一个发，超过的排队，排队太久或者队列满了返回429
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// 每秒最多多少个请求，不配置时不限
+    pub max_rps: Option<f64>,
+    /// 上一个请求结束到下一个请求开始的最小间隔
+    pub min_gap_ms: u64,
+    pub max_queue: usize,
+    /// 排队超过这个时间还没轮到就放弃
+    pub queue_timeout_ms: u64,
+    /// 低优先级的请求排队超过这个时间后不再被高优先级插队，防止饿死
+    pub starvation_ms: u64,
+}
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            max_rps: None,
+            min_gap_ms: 0,
+            max_queue: 64,
+            queue_timeout_ms: 5000,
+            starvation_ms: 1000,
+        }
+    }
+}
+
+/// 允许写的地址，可以是单个地址`10`或者闭区间`[10, 20]`
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(untagged)]
+pub enum AddressRange {
+    Single(u16),
+    Range([u16; 2]),
+}
+impl AddressRange {
+    pub fn contains(&self, address: u16) -> bool {
+        match *self {
+            AddressRange::Single(single) => single == address,
+            AddressRange::Range([start, end]) => (start..=end).contains(&address),
+        }
+    }
+}
+
+/// 单个寄存器允许写入的取值范围
+#[derive(Debug, Deserialize, Clone)]
+pub struct RegisterLimit {
+    pub address: u16,
+    pub min: Option<u16>,
+    pub max: Option<u16>,
+}
+
+/// 联锁：只有`require_register`的值等于`require_value`时才允许写`register`
+#[derive(Debug, Deserialize, Clone)]
+pub struct Interlock {
+    pub register: u16,
+    pub require_register: u16,
+    pub require_value: u16,
+    #[serde(default)]
+    pub description: String,
+}
+
+/// 设备的写入策略，不配置时允许写任意地址
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct WritePolicyConfig {
+    pub read_only: bool,
+    /// 为空时不限制地址
+    pub allowed: Vec<AddressRange>,
+    pub limits: Vec<RegisterLimit>,
+    pub interlocks: Vec<Interlock>,
+}
+
+/// Modbus/TCP Security客户端配置，客户端证书里的角色由设备端做授权
+#[derive(Debug, Deserialize, Clone)]
+pub struct ModbusTlsConfig {
+    pub ca_file: String,
+    pub cert_file: String,
+    pub key_file: String,
+    /// SNI和校验证书用的名字，不配置时用address里的IP
+    pub server_name: Option<String>,
+}
+
+/// 后台健康探测配置，每个设备独立
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ProbeConfig {
+    /// 探测时读取的保持寄存器地址
+    pub register: u16,
+    pub interval_ms: u64,
+    pub timeout_ms: u64,
+    /// 连续失败多少次后认为设备down，之前为degraded
+    pub down_after: u32,
+}
+impl Default for ProbeConfig {
+    fn default() -> Self {
+        ProbeConfig {
+            register: 0,
+            interval_ms: 5000,
+            timeout_ms: 1000,
+            down_after: 3,
+        }
+    }
+}
+
+/// 断路器配置，连续失败`failure_threshold`次后打开，`cooldown_ms`后放行一个试探请求
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct BreakerConfig {
+    pub failure_threshold: u32,
+    pub cooldown_ms: u64,
+}
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        BreakerConfig {
+            failure_threshold: 3,
+            cooldown_ms: 10000,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct ModbusConfig {
     pub configs: Vec<Modbus>,
 }
+#[derive(Debug, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum OtlpProtocol {
+    #[default]
+    Grpc,
+    HttpProtobuf,
+}
+
+/// OTLP配置，没有配置`endpoint`时不启用任何OTLP导出
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct TelemetryConfig {
+    pub endpoint: Option<String>,
+    pub protocol: OtlpProtocol,
+    pub traces: bool,
+    pub logs: bool,
+    pub metrics: bool,
+    pub headers: HashMap<String, String>,
+    /// 根span的采样比例，有父span时跟随父span
+    pub sampling_ratio: f64,
+    pub service_name: String,
+    /// 额外的resource属性，比如`deployment.environment`
+    pub resource: HashMap<String, String>,
+    pub metrics_interval_ms: u64,
+}
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        TelemetryConfig {
+            endpoint: None,
+            protocol: OtlpProtocol::Grpc,
+            traces: true,
+            logs: true,
+            metrics: true,
+            headers: HashMap::new(),
+            sampling_ratio: 1.0,
+            service_name: "modbus".to_string(),
+            resource: HashMap::new(),
+            metrics_interval_ms: 3000,
+        }
+    }
+}
+
+/// 历史数据，按设备/数据点/天分文件追加写入JSON lines
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct HistoryConfig {
+    pub directory: String,
+    pub retention_days: u32,
+    /// 值一直没变时，至少隔这么久存一次，查询时能知道数据点还在更新
+    pub max_interval_secs: u64,
+}
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig {
+            directory: "history".to_string(),
+            retention_days: 30,
+            max_interval_secs: 600,
+        }
+    }
+}
+
+/// webhook通知，事件先写进发件箱目录，发送成功后删除，重启后继续发
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct WebhooksConfig {
+    pub outbox_dir: String,
+    pub sinks: Vec<WebhookSinkConfig>,
+}
+impl Default for WebhooksConfig {
+    fn default() -> Self {
+        WebhooksConfig {
+            outbox_dir: "outbox".to_string(),
+            sinks: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct WebhookSinkConfig {
+    pub name: String,
+    pub url: String,
+    #[serde(default = "default_webhook_method")]
+    pub method: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// 请求体模板，`{{字段}}`替换成事件的字段，不配置时发送整个事件的JSON
+    pub body: Option<String>,
+    /// 只发送这些类型的事件（alarm、device_state），为空时全部发送
+    #[serde(default)]
+    pub events: Vec<String>,
+    #[serde(default = "default_webhook_timeout_ms")]
+    pub timeout_ms: u64,
+    /// 最多尝试多少次，之后移到发件箱的failed目录
+    #[serde(default = "default_webhook_max_attempts")]
+    pub max_attempts: u32,
+    /// 第一次重试的间隔，之后每次翻倍，不超过max_backoff_ms
+    #[serde(default = "default_webhook_backoff_ms")]
+    pub backoff_ms: u64,
+    #[serde(default = "default_webhook_max_backoff_ms")]
+    pub max_backoff_ms: u64,
+}
+fn default_webhook_method() -> String {
+    "POST".to_string()
+}
+fn default_webhook_timeout_ms() -> u64 {
+    5000
+}
+fn default_webhook_max_attempts() -> u32 {
+    10
+}
+fn default_webhook_backoff_ms() -> u64 {
+    1000
+}
+fn default_webhook_max_backoff_ms() -> u64 {
+    300_000
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum MqttPayload {
+    /// 每个数据点一条消息，内容是数据点当前值的JSON
+    #[default]
+    Json,
+    /// 每个设备一条消息，类似Sparkplug B的DDATA，metrics里是变化的数据点
+    Sparkplug,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum MqttPublishMode {
+    /// 值或质量变化时发布
+    #[default]
+    OnChange,
+    /// 每个周期发布全部数据点
+    Periodic,
+}
+
+/// MQTT桥：把轮询到的数据点发布到broker，可选地从命令主题接收写入
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub keep_alive_secs: u64,
+    /// json时的主题模板，`{device}`、`{tag}`替换成设备名和数据点名
+    pub topic: String,
+    pub payload: MqttPayload,
+    /// sparkplug时发布到`spBv1.0/{group_id}/DDATA/{node_id}/{设备名}`
+    pub group_id: String,
+    pub node_id: String,
+    /// 0、1、2
+    pub qos: u8,
+    pub retain: bool,
+    pub mode: MqttPublishMode,
+    /// on_change时多久检查一次变化，periodic时的发布周期
+    pub interval_ms: u64,
+    /// 命令主题模板，`{device}`、`{tag}`必须是完整的一级，不配置时不接收命令；
+    /// 按QoS 0订阅，命令最多执行一次，发送方收不到`/result`时自己决定是否重发
+    pub command_topic: Option<String>,
+}
+impl Default for MqttConfig {
+    fn default() -> Self {
+        MqttConfig {
+            host: "127.0.0.1".to_string(),
+            port: 1883,
+            client_id: "modbus-gateway".to_string(),
+            username: None,
+            password: None,
+            keep_alive_secs: 30,
+            topic: "plant/{device}/{tag}".to_string(),
+            payload: MqttPayload::Json,
+            group_id: "plant".to_string(),
+            node_id: "modbus-gateway".to_string(),
+            qos: 1,
+            retain: false,
+            mode: MqttPublishMode::OnChange,
+            interval_ms: 1000,
+            command_topic: None,
+        }
+    }
+}
+
+/// Modbus TCP服务端：把下游设备重新暴露给只会Modbus TCP的HMI
+#[derive(Debug, Deserialize, Clone)]
+pub struct ModbusServerConfig {
+    pub listen: String,
+    pub mappings: Vec<ModbusServerMapping>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ModbusServerSource {
+    /// 用后台轮询的缓存回答保持寄存器读，其它读请求实时转发
+    #[default]
+    Cache,
+    /// 全部实时转发给设备
+    Live,
+}
+
+/// 一个unit id上的一段地址映射到设备上的一段地址
+#[derive(Debug, Deserialize, Clone)]
+pub struct ModbusServerMapping {
+    pub unit_id: u8,
+    pub device: String,
+    /// 对外的起始地址和数量，不配置时是整个地址空间
+    #[serde(default)]
+    pub start: u16,
+    #[serde(default = "default_mapping_count")]
+    pub count: u32,
+    /// 对应设备上的起始地址，不配置时和start相同
+    pub target_start: Option<u16>,
+    #[serde(default)]
+    pub source: ModbusServerSource,
+    /// 是否允许写保持寄存器，写入经过写入策略检查并记审计日志
+    #[serde(default)]
+    pub writable: bool,
+}
+fn default_mapping_count() -> u32 {
+    0x10000
+}
+
+/// 设备的Modbus TCP透明代理，请求的PDU原样转发给设备，响应的PDU原样返回
+#[derive(Debug, Deserialize, Clone)]
+pub struct ProxyConfig {
+    pub listen: String,
+    /// 是否允许写功能码（5、6、15、16、22、23），写入经过写入策略和联锁检查并记审计日志；
+    /// 读功能码1、2、3、4和读设备标识以外的功能码一律不转发
+    #[serde(default)]
+    pub writable: bool,
+    /// 记录收发的原始报文
+    #[serde(default)]
+    pub log_frames: bool,
+}
+
+/// 告警日志，记录告警的产生、恢复和确认
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct AlarmJournalConfig {
+    pub directory: String,
+    pub file_name: String,
+    /// 内存里保留多少条供/alarms/journal查询
+    pub memory_entries: usize,
+}
+impl Default for AlarmJournalConfig {
+    fn default() -> Self {
+        AlarmJournalConfig {
+            directory: "logs".to_string(),
+            file_name: "alarms.jsonl".to_string(),
+            memory_entries: 1000,
+        }
+    }
+}
+
+/// 审计日志，和应用日志放在同一个目录下，单独一个文件
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct AuditConfig {
+    pub directory: String,
+    pub file_name: String,
+    /// 内存里保留多少条供/audit查询
+    pub memory_entries: usize,
+}
+impl Default for AuditConfig {
+    fn default() -> Self {
+        AuditConfig {
+            directory: "logs".to_string(),
+            file_name: "audit.jsonl".to_string(),
+            memory_entries: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Pretty,
+    Compact,
+    Json,
+    Bunyan,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogTarget {
+    Stdout,
+    File,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Minutely,
+    Hourly,
+    Daily,
+    Never,
+}
+
+/// 日志输出配置，设置了`RUST_LOG`环境变量时优先用它代替`filter`
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct LoggingConfig {
+    pub format: LogFormat,
+    pub targets: Vec<LogTarget>,
+    pub directory: String,
+    pub file_name: String,
+    pub rotation: LogRotation,
+    /// 最多保留多少个轮转出来的日志文件，不配置时不删除
+    pub max_files: Option<usize>,
+    /// EnvFilter指令，比如"info,modbus=debug"
+    pub filter: String,
+}
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            format: LogFormat::Compact,
+            targets: vec![LogTarget::Stdout, LogTarget::File],
+            directory: "logs".to_string(),
+            file_name: "app.log".to_string(),
+            rotation: LogRotation::Daily,
+            max_files: None,
+            filter: "debug".to_string(),
+        }
+    }
+}
+
+/// 角色从低到高，高的角色包含低的角色的权限
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Viewer,
+    Operator,
+    Admin,
+}
+
+/// 静态API key，`devices`不配置时可以访问所有设备
+#[derive(Debug, Deserialize, Clone)]
+pub struct ApiKeyConfig {
+    pub name: String,
+    pub key: String,
+    pub role: Role,
+    pub devices: Option<Vec<String>>,
+}
+
+/// 用本地密钥校验的JWT，HS*用`secret`，RS*/ES*/EdDSA用`public_key_file`
+#[derive(Debug, Deserialize, Clone)]
+pub struct JwtConfig {
+    pub algorithm: Algorithm,
+    pub secret: Option<String>,
+    pub public_key_file: Option<String>,
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+    #[serde(default = "default_role_claim")]
+    pub role_claim: String,
+    #[serde(default = "default_devices_claim")]
+    pub devices_claim: String,
+    #[serde(default)]
+    pub leeway_secs: u64,
+}
+fn default_role_claim() -> String {
+    "role".to_string()
+}
+fn default_devices_claim() -> String {
+    "devices".to_string()
+}
+
+/// 没有开启时所有请求都当作admin，和之前的行为一致
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct AuthConfig {
+    pub enabled: bool,
+    pub api_keys: Vec<ApiKeyConfig>,
+    pub jwt: Option<JwtConfig>,
+}
+
+/// 路线命令的选择-执行（select-before-operate），先选择拿到令牌，令牌过期前再执行
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct SelectBeforeOperateConfig {
+    /// 为true时不允许直接POST /route，必须先选择再执行
+    pub required: bool,
+    pub ttl_ms: u64,
+}
+impl Default for SelectBeforeOperateConfig {
+    fn default() -> Self {
+        SelectBeforeOperateConfig {
+            required: false,
+            ttl_ms: 10000,
+        }
+    }
+}
+
+/// 写接口的Idempotency-Key，相同key在窗口内重复请求时直接返回第一次的结果
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct IdempotencyConfig {
+    pub window_secs: u64,
+}
+impl Default for IdempotencyConfig {
+    fn default() -> Self {
+        IdempotencyConfig { window_secs: 600 }
+    }
+}
+
+/// 流水线路线命令：从`from`到`to`时往设备`device`的`register`写`value`
+#[derive(Debug, Deserialize, Clone)]
+pub struct RouteCommand {
+    pub from: String,
+    pub to: String,
+    pub device: String,
+    pub register: u16,
+    pub value: u16,
+    #[serde(default)]
+    pub description: String,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct AppConfig {
     pub server: ServerConfig,
     pub modbus: ModbusConfig,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    #[serde(default)]
+    pub telemetry: TelemetryConfig,
+    #[serde(default)]
+    pub audit: AuditConfig,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub routes: Vec<RouteCommand>,
+    #[serde(default)]
+    pub select_before_operate: SelectBeforeOperateConfig,
+    #[serde(default)]
+    pub idempotency: IdempotencyConfig,
+    #[serde(default)]
+    pub history: HistoryConfig,
+    #[serde(default)]
+    pub alarm_journal: AlarmJournalConfig,
+    #[serde(default)]
+    pub webhooks: WebhooksConfig,
+    /// 不配置时不启用MQTT
+    pub mqtt: Option<MqttConfig>,
+    /// 不配置时不监听Modbus TCP
+    pub modbus_server: Option<ModbusServerConfig>,
 }
 
 pub fn load_config() -> Result<AppConfig, ConfigError> {
@@ -0,0 +1,39 @@
+use std::io;
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::{app_config::AuditConfig, journal::Journal};
+
+/// 一条写操作的审计记录，写入后不会再修改
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AuditRecord {
+    pub timestamp: DateTime<Local>,
+    pub client_ip: Option<String>,
+    pub identity: Option<String>,
+    pub device: String,
+    pub unit_id: u8,
+    pub address: u16,
+    /// 写之前读到的值，读失败时为None
+    pub old_values: Option<Vec<u16>>,
+    pub new_values: Vec<u16>,
+    /// 成功为"success"，否则为错误描述
+    pub outcome: String,
+    pub trace_id: Option<String>,
+    /// 通过路线命令写入时记录路线，如"5103-1-1-1->5106-1-1-1"；
+    /// 透明代理写入时记录功能码，如"功能码15"，功能码5和15的地址是线圈地址
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+}
+
+/// 审计日志：追加写入JSON lines文件，同时在内存里保留最近的记录供查询
+pub type AuditLog = Journal<AuditRecord>;
+
+pub fn open(config: &AuditConfig) -> io::Result<AuditLog> {
+    Journal::open(
+        "审计日志",
+        &config.directory,
+        &config.file_name,
+        config.memory_entries,
+    )
+}
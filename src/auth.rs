@@ -0,0 +1,301 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, fs,
+    future::{ready, Ready},
+};
+
+use actix_web::{
+    body::MessageBody,
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    http::{header, StatusCode},
+    middleware::Next,
+    web, Error, FromRequest, HttpMessage, HttpRequest, HttpResponse, ResponseError,
+};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde_json::Value;
+use tracing::{debug, warn};
+
+use crate::app_config::{ApiKeyConfig, AuthConfig, JwtConfig, Role};
+use crate::server_router::Response;
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+/// 认证后的调用方，由`auth_middleware`放进请求的extensions里
+#[derive(Clone, Debug)]
+pub struct Identity {
+    pub name: String,
+    pub role: Role,
+    /// 可以访问的设备，None表示所有设备
+    pub devices: Option<HashSet<String>>,
+}
+
+impl Identity {
+    /// 没有开启认证时的身份，拥有所有权限
+    fn anonymous() -> Self {
+        Identity {
+            name: "anonymous".to_string(),
+            role: Role::Admin,
+            devices: None,
+        }
+    }
+
+    pub fn can_access(&self, device: &str) -> bool {
+        self.devices
+            .as_ref()
+            .is_none_or(|devices| devices.contains(device))
+    }
+
+    /// 检查角色，`device`不为None时同时检查能否访问这个设备
+    pub fn authorize(&self, role: Role, device: Option<&str>) -> Result<(), AuthError> {
+        if self.role < role {
+            return Err(AuthError::Forbidden(role));
+        }
+        match device {
+            Some(device) if !self.can_access(device) => {
+                Err(AuthError::DeviceForbidden(device.to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl FromRequest for Identity {
+    type Error = AuthError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<Identity>()
+                .cloned()
+                .ok_or(AuthError::Unauthenticated),
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    Unauthenticated,
+    InvalidCredentials(String),
+    Forbidden(Role),
+    DeviceForbidden(String),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Unauthenticated => write!(f, "未认证，请提供API key或者bearer token"),
+            AuthError::InvalidCredentials(reason) => write!(f, "认证失败：{}", reason),
+            AuthError::Forbidden(role) => write!(f, "权限不足，需要{:?}角色", role),
+            AuthError::DeviceForbidden(device) => write!(f, "没有访问设备{}的权限", device),
+        }
+    }
+}
+
+impl ResponseError for AuthError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AuthError::Unauthenticated | AuthError::InvalidCredentials(_) => {
+                StatusCode::UNAUTHORIZED
+            }
+            AuthError::Forbidden(_) | AuthError::DeviceForbidden(_) => StatusCode::FORBIDDEN,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        if self.status_code() == StatusCode::UNAUTHORIZED {
+            response.insert_header((header::WWW_AUTHENTICATE, "Bearer"));
+        }
+        response.json(Response::error(self.to_string()))
+    }
+}
+
+/// 一种认证方式。请求里没有这种方式的凭据时返回None，交给下一种方式
+pub trait AuthProvider: Send + Sync {
+    fn authenticate(&self, req: &ServiceRequest) -> Option<Result<Identity, AuthError>>;
+}
+
+fn bearer_token(req: &ServiceRequest) -> Option<&str> {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// `X-API-Key`请求头，或者把API key当bearer token（方便Prometheus抓取）
+pub struct ApiKeyProvider {
+    keys: Vec<ApiKeyConfig>,
+}
+
+impl ApiKeyProvider {
+    fn find(&self, key: &str) -> Option<Identity> {
+        self.keys
+            .iter()
+            .find(|config| constant_time_eq(config.key.as_bytes(), key.as_bytes()))
+            .map(|config| Identity {
+                name: config.name.clone(),
+                role: config.role,
+                devices: config
+                    .devices
+                    .as_ref()
+                    .map(|devices| devices.iter().cloned().collect()),
+            })
+    }
+}
+
+impl AuthProvider for ApiKeyProvider {
+    fn authenticate(&self, req: &ServiceRequest) -> Option<Result<Identity, AuthError>> {
+        if let Some(key) = req.headers().get(API_KEY_HEADER) {
+            let key = key.to_str().unwrap_or_default();
+            return Some(
+                self.find(key)
+                    .ok_or_else(|| AuthError::InvalidCredentials("API key无效".to_string())),
+            );
+        }
+        // bearer token不是API key时交给JWT
+        bearer_token(req).and_then(|token| self.find(token)).map(Ok)
+    }
+}
+
+pub struct JwtProvider {
+    key: DecodingKey,
+    validation: Validation,
+    role_claim: String,
+    devices_claim: String,
+}
+
+impl JwtProvider {
+    pub fn new(config: &JwtConfig) -> Result<Self, String> {
+        let key = match config.algorithm {
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+                let secret = config.secret.as_ref().ok_or("HS*算法需要配置jwt.secret")?;
+                DecodingKey::from_secret(secret.as_bytes())
+            }
+            algorithm => {
+                let path = config
+                    .public_key_file
+                    .as_ref()
+                    .ok_or("非对称算法需要配置jwt.public_key_file")?;
+                let pem = fs::read(path).map_err(|err| format!("读取{}失败：{}", path, err))?;
+                match algorithm {
+                    Algorithm::ES256 | Algorithm::ES384 => DecodingKey::from_ec_pem(&pem),
+                    Algorithm::EdDSA => DecodingKey::from_ed_pem(&pem),
+                    _ => DecodingKey::from_rsa_pem(&pem),
+                }
+                .map_err(|err| format!("解析{}失败：{}", path, err))?
+            }
+        };
+        let mut validation = Validation::new(config.algorithm);
+        validation.leeway = config.leeway_secs;
+        if let Some(issuer) = &config.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &config.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        Ok(JwtProvider {
+            key,
+            validation,
+            role_claim: config.role_claim.clone(),
+            devices_claim: config.devices_claim.clone(),
+        })
+    }
+
+    fn verify(&self, token: &str) -> Result<Identity, AuthError> {
+        let claims =
+            jsonwebtoken::decode::<HashMap<String, Value>>(token, &self.key, &self.validation)
+                .map_err(|err| AuthError::InvalidCredentials(format!("token无效：{}", err)))?
+                .claims;
+        let role = claims
+            .get(&self.role_claim)
+            .cloned()
+            .and_then(|role| serde_json::from_value::<Role>(role).ok())
+            .ok_or_else(|| {
+                AuthError::InvalidCredentials(format!("token里没有有效的{}", self.role_claim))
+            })?;
+        let devices = claims
+            .get(&self.devices_claim)
+            .and_then(Value::as_array)
+            .map(|devices| {
+                devices
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .map(str::to_string)
+                    .collect()
+            });
+        Ok(Identity {
+            name: claims
+                .get("sub")
+                .and_then(Value::as_str)
+                .unwrap_or("jwt")
+                .to_string(),
+            role,
+            devices,
+        })
+    }
+}
+
+impl AuthProvider for JwtProvider {
+    fn authenticate(&self, req: &ServiceRequest) -> Option<Result<Identity, AuthError>> {
+        bearer_token(req).map(|token| self.verify(token))
+    }
+}
+
+pub struct Authenticator {
+    enabled: bool,
+    providers: Vec<Box<dyn AuthProvider>>,
+}
+
+impl Authenticator {
+    pub fn new(config: &AuthConfig) -> Result<Self, String> {
+        let mut providers: Vec<Box<dyn AuthProvider>> = Vec::new();
+        if !config.api_keys.is_empty() {
+            providers.push(Box::new(ApiKeyProvider {
+                keys: config.api_keys.clone(),
+            }));
+        }
+        if let Some(jwt) = &config.jwt {
+            providers.push(Box::new(JwtProvider::new(jwt)?));
+        }
+        if !config.enabled {
+            warn!("HTTP接口没有开启认证，所有请求都当作admin，可以写寄存器、执行路线命令和调用/admin接口");
+        } else if providers.is_empty() {
+            warn!("HTTP接口开启了认证但没有配置api_keys或jwt，所有需要权限的接口都会返回401");
+        }
+        Ok(Authenticator {
+            enabled: config.enabled,
+            providers,
+        })
+    }
+
+    /// 请求里没有任何凭据时返回Ok(None)，需要权限的接口会在提取Identity时返回401
+    fn authenticate(&self, req: &ServiceRequest) -> Result<Option<Identity>, AuthError> {
+        if !self.enabled {
+            return Ok(Some(Identity::anonymous()));
+        }
+        self.providers
+            .iter()
+            .find_map(|provider| provider.authenticate(req))
+            .transpose()
+    }
+}
+
+pub async fn auth_middleware(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    if let Some(authenticator) = req.app_data::<web::Data<Authenticator>>().cloned() {
+        if let Some(identity) = authenticator.authenticate(&req)? {
+            debug!("认证通过：{} ({:?})", identity.name, identity.role);
+            req.extensions_mut().insert(identity);
+        }
+    }
+    next.call(req).await
+}
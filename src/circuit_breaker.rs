@@ -0,0 +1,207 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use opentelemetry::KeyValue;
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::{app_config::BreakerConfig, metrics::METRICS};
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+impl BreakerState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BreakerState::Closed => "closed",
+            BreakerState::Open => "open",
+            BreakerState::HalfOpen => "half_open",
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct BreakerSnapshot {
+    pub state: BreakerState,
+    pub consecutive_failures: u32,
+    /// 断路器打开时，距离允许试探请求还剩多少毫秒
+    pub retry_after_ms: Option<u64>,
+}
+
+struct Inner {
+    state: BreakerState,
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    /// half-open时只放行一个试探请求
+    trial_in_flight: bool,
+}
+
+/// 每个设备一个断路器，连续失败达到阈值后快速失败，冷却后放行一个试探请求
+pub struct CircuitBreaker {
+    name: String,
+    failure_threshold: u32,
+    cooldown: Duration,
+    inner: Mutex<Inner>,
+}
+
+/// 断路器打开，请求被直接拒绝
+#[derive(Debug)]
+pub struct BreakerOpen {
+    pub retry_after: Duration,
+}
+
+impl CircuitBreaker {
+    pub fn new(name: impl Into<String>, config: &BreakerConfig) -> Self {
+        CircuitBreaker {
+            name: name.into(),
+            failure_threshold: config.failure_threshold.max(1),
+            cooldown: Duration::from_millis(config.cooldown_ms),
+            inner: Mutex::new(Inner {
+                state: BreakerState::Closed,
+                consecutive_failures: 0,
+                opened_at: None,
+                trial_in_flight: false,
+            }),
+        }
+    }
+
+    /// 请求前调用，返回的许可需要在请求结束后报告结果
+    pub fn try_acquire(&self) -> Result<BreakerPermit<'_>, BreakerOpen> {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.state {
+            BreakerState::Closed => {}
+            BreakerState::Open => {
+                let elapsed = inner.opened_at.map(|at| at.elapsed()).unwrap_or_default();
+                if elapsed < self.cooldown {
+                    return Err(BreakerOpen {
+                        retry_after: self.cooldown - elapsed,
+                    });
+                }
+                self.transition(&mut inner, BreakerState::HalfOpen);
+                inner.trial_in_flight = true;
+            }
+            BreakerState::HalfOpen => {
+                if inner.trial_in_flight {
+                    return Err(BreakerOpen {
+                        retry_after: Duration::ZERO,
+                    });
+                }
+                inner.trial_in_flight = true;
+            }
+        }
+        Ok(BreakerPermit {
+            breaker: self,
+            done: false,
+        })
+    }
+
+    /// 只看断路器会不会拒绝请求，不占用试探名额；排队之前调用，断路器打开时不用排队就失败
+    pub fn check(&self) -> Result<(), BreakerOpen> {
+        let inner = self.inner.lock().unwrap();
+        match inner.state {
+            BreakerState::Closed => Ok(()),
+            BreakerState::Open => {
+                let elapsed = inner.opened_at.map(|at| at.elapsed()).unwrap_or_default();
+                match self.cooldown.checked_sub(elapsed) {
+                    Some(retry_after) if !retry_after.is_zero() => Err(BreakerOpen { retry_after }),
+                    _ => Ok(()),
+                }
+            }
+            BreakerState::HalfOpen if inner.trial_in_flight => Err(BreakerOpen {
+                retry_after: Duration::ZERO,
+            }),
+            BreakerState::HalfOpen => Ok(()),
+        }
+    }
+
+    pub fn snapshot(&self) -> BreakerSnapshot {
+        let inner = self.inner.lock().unwrap();
+        let retry_after_ms = match (inner.state, inner.opened_at) {
+            (BreakerState::Open, Some(at)) => {
+                Some(self.cooldown.saturating_sub(at.elapsed()).as_millis() as u64)
+            }
+            _ => None,
+        };
+        BreakerSnapshot {
+            state: inner.state,
+            consecutive_failures: inner.consecutive_failures,
+            retry_after_ms,
+        }
+    }
+
+    fn on_success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.consecutive_failures = 0;
+        inner.trial_in_flight = false;
+        inner.opened_at = None;
+        self.transition(&mut inner, BreakerState::Closed);
+    }
+
+    fn on_failure(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.consecutive_failures += 1;
+        inner.trial_in_flight = false;
+        let open = match inner.state {
+            BreakerState::HalfOpen => true,
+            BreakerState::Closed => inner.consecutive_failures >= self.failure_threshold,
+            BreakerState::Open => false,
+        };
+        if open {
+            inner.opened_at = Some(Instant::now());
+            self.transition(&mut inner, BreakerState::Open);
+        }
+    }
+
+    fn transition(&self, inner: &mut Inner, to: BreakerState) {
+        if inner.state == to {
+            return;
+        }
+        match to {
+            BreakerState::Open => warn!(
+                "设备{}断路器打开，连续失败{}次，{:?}内请求直接失败",
+                self.name, inner.consecutive_failures, self.cooldown
+            ),
+            _ => info!("设备{}断路器{:?} -> {:?}", self.name, inner.state, to),
+        }
+        METRICS.breaker_transitions.add(
+            1,
+            &[
+                KeyValue::new("device", self.name.clone()),
+                KeyValue::new("state", to.as_str()),
+            ],
+        );
+        inner.state = to;
+    }
+}
+
+/// 放行的请求，结束后调用`success`或`failure`；直接丢弃（如请求被取消）时不计入结果
+pub struct BreakerPermit<'a> {
+    breaker: &'a CircuitBreaker,
+    done: bool,
+}
+
+impl BreakerPermit<'_> {
+    pub fn success(mut self) {
+        self.done = true;
+        self.breaker.on_success();
+    }
+    pub fn failure(mut self) {
+        self.done = true;
+        self.breaker.on_failure();
+    }
+}
+
+impl Drop for BreakerPermit<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            // 试探请求没有结果，让下一个请求继续试探
+            self.breaker.inner.lock().unwrap().trial_in_flight = false;
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use std::{borrow::Cow, collections::HashMap, fmt};
+
+use chrono::Local;
+use opentelemetry::trace::TraceContextExt;
+use tokio_modbus::Request;
+use tracing::{info, warn, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::{
+    app_config::RouteCommand,
+    audit::{AuditLog, AuditRecord},
+    device::{Device, DeviceError},
+    rate_limiter::Priority,
+    write_policy::{self, PolicyViolation},
+};
+
+/// 写命令的来源，用于审计
+#[derive(Clone, Debug, Default)]
+pub struct Origin {
+    pub client_ip: Option<String>,
+    pub identity: Option<String>,
+    /// 路线命令的描述，或者透明代理写入的功能码，普通寄存器写入时为None
+    pub command: Option<String>,
+}
+
+/// 路线命令表，(起点, 终点) -> 要写的寄存器
+pub struct RouteTable(HashMap<(String, String), RouteCommand>);
+
+impl RouteTable {
+    pub fn new(routes: &[RouteCommand]) -> Self {
+        RouteTable(
+            routes
+                .iter()
+                .map(|route| ((route.from.clone(), route.to.clone()), route.clone()))
+                .collect(),
+        )
+    }
+
+    pub fn get(&self, from: &str, to: &str) -> Option<&RouteCommand> {
+        self.0.get(&(from.to_string(), to.to_string()))
+    }
+}
+
+#[derive(Debug)]
+pub enum WriteError {
+    /// 写入策略拒绝，没有发给设备
+    Policy(PolicyViolation),
+    Device(DeviceError),
+}
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WriteError::Policy(violation) => write!(f, "写入策略拒绝：{}", violation),
+            WriteError::Device(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// 所有写操作的统一入口：先按写入策略检查，再读旧值、写入，最后记审计日志；
+/// 联锁检查、读旧值和写入在同一个限速许可里完成
+pub async fn write_registers(
+    device: &Device,
+    address: u16,
+    values: Vec<u16>,
+    origin: &Origin,
+    audit: &AuditLog,
+) -> Result<(), WriteError> {
+    let reject = |violation: PolicyViolation, values: Vec<u16>| {
+        warn!(
+            "设备{}地址{}写入{:?}被拒绝：{}",
+            device.name, address, values, violation
+        );
+        audit.record(audit_record(
+            device,
+            address,
+            None,
+            values,
+            format!("rejected: {}", violation),
+            origin,
+        ));
+        Err(WriteError::Policy(violation))
+    };
+    if let Err(violation) = write_policy::check(device, address, &values) {
+        return reject(violation, values);
+    }
+    let turn = match device.turn(Priority::Control).await {
+        Ok(turn) => turn,
+        Err(err) => {
+            audit.record(audit_record(
+                device,
+                address,
+                None,
+                values,
+                err.to_string(),
+                origin,
+            ));
+            return Err(WriteError::Device(err));
+        }
+    };
+    if let Err(violation) = write_policy::check_interlocks(&turn, address, values.len()).await {
+        return reject(violation, values);
+    }
+    let count = values.len() as u16;
+    let old_values = match turn.read_holding_registers(address, count).await {
+        Ok(old) => Some(old),
+        Err(err) => {
+            warn!(
+                "写入前读取设备{}地址{}的旧值失败：{}",
+                device.name, address, err
+            );
+            None
+        }
+    };
+    let request = if values.len() == 1 {
+        Request::WriteSingleRegister(address, values[0])
+    } else {
+        Request::WriteMultipleRegisters(address, Cow::Owned(values.clone()))
+    };
+    let result = turn.call(request).await.map(|_| ());
+    drop(turn);
+    let outcome = match &result {
+        Ok(()) => {
+            info!(
+                "设备{}地址{}写入{:?}成功，旧值{:?}",
+                device.name, address, values, old_values
+            );
+            "success".to_string()
+        }
+        Err(err) => err.to_string(),
+    };
+    audit.record(audit_record(
+        device, address, old_values, values, outcome, origin,
+    ));
+    result.map_err(WriteError::Device)
+}
+
+pub fn audit_record(
+    device: &Device,
+    address: u16,
+    old_values: Option<Vec<u16>>,
+    new_values: Vec<u16>,
+    outcome: String,
+    origin: &Origin,
+) -> AuditRecord {
+    AuditRecord {
+        timestamp: Local::now(),
+        client_ip: origin.client_ip.clone(),
+        identity: origin.identity.clone(),
+        device: device.name.clone(),
+        unit_id: device.slave,
+        address,
+        old_values,
+        new_values,
+        outcome,
+        trace_id: current_trace_id(),
+        command: origin.command.clone(),
+    }
+}
+
+fn current_trace_id() -> Option<String> {
+    let context = Span::current().context();
+    let span_context = context.span().span_context().clone();
+    span_context
+        .is_valid()
+        .then(|| format!("{:032x}", span_context.trace_id()))
+}
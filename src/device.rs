@@ -0,0 +1,531 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use actix_web::rt::time::{sleep, timeout};
+use deadpool::managed::{Object, PoolError};
+use opentelemetry::KeyValue;
+use tokio_modbus::{client::Client, ExceptionCode, FunctionCode, Request, Response};
+use tracing::{field, info_span, Instrument};
+
+use crate::{
+    app_config::{self, PollConfig, ProbeConfig, TagConfig, WritePolicyConfig},
+    circuit_breaker::{BreakerPermit, CircuitBreaker},
+    device_health::DeviceHealth,
+    metrics::METRICS,
+    modbus_manager::{self, ModbusManager, Pool},
+    modbus_tls::ModbusTls,
+    poller::PollCache,
+    rate_limiter::{LimiterPermit, Priority, RateLimited, RateLimiter},
+};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// 一个配置好的modbus设备：连接池以及运行时状态
+pub struct Device {
+    pub name: String,
+    pub addr: String,
+    pub slave: u8,
+    pub probe: ProbeConfig,
+    pub pool: Pool,
+    pub health: DeviceHealth,
+    pub breaker: CircuitBreaker,
+    /// Modbus/TCP Security客户端证书里的角色，不是TLS连接时为None
+    pub tls_role: Option<String>,
+    pub write_policy: WritePolicyConfig,
+    pub limiter: RateLimiter,
+    pub poll: PollConfig,
+    pub tags: Vec<TagConfig>,
+    /// 后台轮询到的值
+    pub cache: PollCache,
+}
+pub type Devices = HashMap<String, Arc<Device>>;
+
+/// 一次限速许可，持有期间可以连续发多个请求，别的请求插不进来
+pub struct Turn<'a> {
+    device: &'a Device,
+    priority: Priority,
+    /// 本次许可里上一个请求的(开始, 结束)时间，下一个请求仍然要满足限速和帧间隔
+    last: Mutex<Option<(Instant, Instant)>>,
+    _permit: LimiterPermit<'a>,
+}
+
+impl<'a> Turn<'a> {
+    pub fn device(&self) -> &'a Device {
+        self.device
+    }
+
+    pub async fn call(&self, request: Request<'static>) -> Result<Response, DeviceError> {
+        self.exchange(Exchange::Request(request))
+            .await
+            .map(Reply::into_response)
+    }
+
+    /// 透明代理用，同`Device::call_raw`
+    pub async fn call_raw(&self, unit_id: u8, pdu: &[u8]) -> Result<Vec<u8>, DeviceError> {
+        self.exchange(Exchange::Raw { unit_id, pdu })
+            .await
+            .map(Reply::into_raw)
+    }
+
+    async fn exchange(&self, exchange: Exchange<'_>) -> Result<Reply, DeviceError> {
+        let last = *self.last.lock().unwrap();
+        if let Some((start, end)) = last {
+            let ready_at = self.device.limiter.next_ready(start, end);
+            sleep(ready_at.saturating_duration_since(Instant::now())).await;
+        }
+        let started = Instant::now();
+        let result = self
+            .device
+            .traced_call(self.priority, exchange, Some(self))
+            .await;
+        *self.last.lock().unwrap() = Some((started, Instant::now()));
+        result
+    }
+
+    pub async fn read_holding_registers(
+        &self,
+        addr: u16,
+        cnt: u16,
+    ) -> Result<Vec<u16>, DeviceError> {
+        match self.call(Request::ReadHoldingRegisters(addr, cnt)).await? {
+            Response::ReadHoldingRegisters(values) => Ok(values),
+            _ => unreachable!("call() should reject mismatching responses"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DeviceError {
+    /// 断路器打开，没有尝试连接
+    Unavailable {
+        name: String,
+        retry_after: Duration,
+    },
+    /// 限速队列满了或者排队超时，没有发给设备
+    RateLimited {
+        name: String,
+        reason: RateLimited,
+    },
+    Pool(PoolError<modbus_manager::Error>),
+    Timeout,
+    Io(tokio_modbus::Error),
+    /// 设备有响应，但返回了异常码
+    Exception(ExceptionCode),
+}
+impl DeviceError {
+    /// 指标里用的错误类型
+    pub fn kind(&self) -> &'static str {
+        match self {
+            DeviceError::Unavailable { .. } => "unavailable",
+            DeviceError::RateLimited { .. } => "rate_limited",
+            DeviceError::Pool(_) => "connect",
+            DeviceError::Timeout => "timeout",
+            DeviceError::Io(_) => "io",
+            DeviceError::Exception(_) => "exception",
+        }
+    }
+}
+impl fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceError::Unavailable { name, retry_after } => write!(
+                f,
+                "device unavailable: 设备{}连续失败，{}ms后再试",
+                name,
+                retry_after.as_millis()
+            ),
+            DeviceError::RateLimited { name, reason } => {
+                write!(f, "设备{}请求太多：{}", name, reason)
+            }
+            DeviceError::Pool(err) => write!(f, "获取modbus连接失败：{:?}", err),
+            DeviceError::Timeout => write!(f, "请求超时"),
+            DeviceError::Io(err) => write!(f, "{}", err),
+            DeviceError::Exception(code) => write!(f, "{}", code),
+        }
+    }
+}
+
+impl Device {
+    pub fn new(config: &app_config::Modbus) -> Result<Self, String> {
+        let tls = config
+            .tls
+            .as_ref()
+            .map(|tls| ModbusTls::new(tls, &config.address).map(Arc::new))
+            .transpose()
+            .map_err(|err| format!("设备{}的TLS配置错误：{}", config.name, err))?;
+        for (index, tag) in config.tags.iter().enumerate() {
+            // 数据点名字会用作历史文件的目录名
+            if !valid_name(&tag.name) {
+                return Err(format!(
+                    "设备{}的数据点名字{:?}不合法，只能包含字母、数字、_、-和.",
+                    config.name, tag.name
+                ));
+            }
+            if config.tags[..index]
+                .iter()
+                .any(|other| other.name == tag.name)
+            {
+                return Err(format!("设备{}的数据点{}重复", config.name, tag.name));
+            }
+        }
+        let tls_role = tls.as_ref().and_then(|tls| tls.role.clone());
+        let mgr = ModbusManager {
+            name: config.name.clone(),
+            addr: config.address.to_string(),
+            slave: config.slave_id,
+            tls,
+        };
+        let pool = Pool::builder(mgr).max_size(1).build().unwrap();
+        Ok(Device {
+            name: config.name.clone(),
+            addr: config.address.clone(),
+            slave: config.slave_id,
+            probe: config.probe.clone(),
+            pool,
+            health: DeviceHealth::new(&config.name, &config.address, config.probe.down_after),
+            breaker: CircuitBreaker::new(config.name.clone(), &config.breaker),
+            tls_role,
+            write_policy: config.write_policy.clone(),
+            limiter: RateLimiter::new(&config.rate_limit),
+            poll: config.poll.clone(),
+            tags: config.tags.clone(),
+            cache: PollCache::default(),
+        })
+    }
+
+    /// 经过断路器从连接池取一个连接，调用方需要用返回的许可报告请求结果
+    pub async fn checkout(
+        &self,
+    ) -> Result<(Object<ModbusManager>, BreakerPermit<'_>), DeviceError> {
+        let status = self.pool.status();
+        let span = info_span!(
+            "modbus.pool.checkout",
+            modbus.device = %self.name,
+            pool.size = status.size,
+            pool.available = status.available,
+            pool.waiting = status.waiting,
+            modbus.latency_ms = field::Empty,
+            error.kind = field::Empty,
+            otel.status_code = field::Empty,
+        );
+        let started = Instant::now();
+        let result = self.checkout_inner().instrument(span.clone()).await;
+        span.record(
+            "modbus.latency_ms",
+            started.elapsed().as_secs_f64() * 1000.0,
+        );
+        if let Err(err) = &result {
+            span.record("error.kind", err.kind());
+            span.record("otel.status_code", "ERROR");
+        }
+        result
+    }
+
+    async fn checkout_inner(
+        &self,
+    ) -> Result<(Object<ModbusManager>, BreakerPermit<'_>), DeviceError> {
+        let permit = self
+            .breaker
+            .try_acquire()
+            .map_err(|open| DeviceError::Unavailable {
+                name: self.name.clone(),
+                retry_after: open.retry_after,
+            })?;
+        match self.pool.get().await {
+            Ok(modbus) => Ok((modbus, permit)),
+            Err(err) => {
+                permit.failure();
+                let err = DeviceError::Pool(err);
+                self.health.record_failure(err.to_string());
+                Err(err)
+            }
+        }
+    }
+
+    /// 排队取得一次限速许可，用于几个请求之间不能被别的请求打断的情况，例如先读联锁再写。
+    /// 断路器打开时直接失败，不占用排队位置和限速额度
+    pub async fn turn(&self, priority: Priority) -> Result<Turn<'_>, DeviceError> {
+        self.breaker
+            .check()
+            .map_err(|open| DeviceError::Unavailable {
+                name: self.name.clone(),
+                retry_after: open.retry_after,
+            })?;
+        let permit =
+            self.limiter
+                .acquire(priority)
+                .await
+                .map_err(|reason| DeviceError::RateLimited {
+                    name: self.name.clone(),
+                    reason,
+                })?;
+        Ok(Turn {
+            device: self,
+            priority,
+            last: Mutex::new(None),
+            _permit: permit,
+        })
+    }
+
+    /// 发送一个modbus请求，统一处理排队优先级、超时、断路器、健康状态和指标
+    pub async fn call(
+        &self,
+        priority: Priority,
+        request: Request<'static>,
+    ) -> Result<Response, DeviceError> {
+        self.traced_call(priority, Exchange::Request(request), None)
+            .await
+            .map(Reply::into_response)
+    }
+
+    /// 透明代理用：原始请求PDU用给定的unit id发给设备，返回设备的原始响应PDU。
+    /// 和`call`一样排队并经过断路器和连接池，设备的异常响应也原样返回，不当作错误
+    pub async fn call_raw(
+        &self,
+        priority: Priority,
+        unit_id: u8,
+        pdu: &[u8],
+    ) -> Result<Vec<u8>, DeviceError> {
+        self.traced_call(priority, Exchange::Raw { unit_id, pdu }, None)
+            .await
+            .map(Reply::into_raw)
+    }
+
+    /// `turn`不为None时已经持有限速许可，不再排队
+    async fn traced_call(
+        &self,
+        priority: Priority,
+        exchange: Exchange<'_>,
+        turn: Option<&Turn<'_>>,
+    ) -> Result<Reply, DeviceError> {
+        let (function, unit_id, range) = match &exchange {
+            Exchange::Request(request) => {
+                (request.function_code(), self.slave, request_range(request))
+            }
+            Exchange::Raw { unit_id, pdu } => (FunctionCode::new(pdu[0]), *unit_id, None),
+        };
+        let (start_address, count) = range.unzip();
+        let span = info_span!(
+            "modbus.request",
+            otel.name = %format!("modbus {:?}", function),
+            modbus.device = %self.name,
+            server.address = %self.addr,
+            modbus.unit_id = unit_id,
+            modbus.function_code = function.value(),
+            modbus.start_address = start_address,
+            modbus.count = count,
+            modbus.priority = priority.as_str(),
+            modbus.exception_code = field::Empty,
+            modbus.latency_ms = field::Empty,
+            error.kind = field::Empty,
+            otel.status_code = field::Empty,
+        );
+        let started = Instant::now();
+        let result = self
+            .call_inner(priority, exchange, turn)
+            .instrument(span.clone())
+            .await;
+        span.record(
+            "modbus.latency_ms",
+            started.elapsed().as_secs_f64() * 1000.0,
+        );
+        let outcome = match &result {
+            // 原始响应里的异常码
+            Ok(Reply::Raw(pdu)) if pdu[0] & 0x80 != 0 => {
+                if let Some(code) = pdu.get(1) {
+                    span.record("modbus.exception_code", *code);
+                }
+                span.record("error.kind", "exception");
+                span.record("otel.status_code", "ERROR");
+                "exception"
+            }
+            Ok(_) => "success",
+            Err(err) => {
+                if let DeviceError::Exception(code) = err {
+                    span.record("modbus.exception_code", u8::from(*code));
+                }
+                span.record("error.kind", err.kind());
+                span.record("otel.status_code", "ERROR");
+                err.kind()
+            }
+        };
+        METRICS.record_request(&self.name, function, outcome, started.elapsed());
+        if outcome != "success" {
+            METRICS.errors.add(
+                1,
+                &[
+                    KeyValue::new("device", self.name.clone()),
+                    KeyValue::new("function_code", function.value() as i64),
+                    KeyValue::new("kind", outcome),
+                ],
+            );
+        }
+        result
+    }
+
+    async fn call_inner(
+        &self,
+        priority: Priority,
+        exchange: Exchange<'_>,
+        turn: Option<&Turn<'_>>,
+    ) -> Result<Reply, DeviceError> {
+        // 限速许可一直持有到请求结束，保证同一个设备上的请求不会重叠
+        let _turn = match turn {
+            Some(_) => None,
+            None => Some(self.turn(priority).await?),
+        };
+        let (mut modbus, permit) = self.checkout().await?;
+        let result = match exchange {
+            Exchange::Request(request) => timeout(REQUEST_TIMEOUT, modbus.context.call(request))
+                .await
+                .map(|result| result.map(|response| response.map(Reply::Response))),
+            Exchange::Raw { unit_id, pdu } => {
+                timeout(REQUEST_TIMEOUT, modbus.call_raw(unit_id, pdu))
+                    .await
+                    .map(|result| result.map(|pdu| Ok(Reply::Raw(pdu))).map_err(Into::into))
+            }
+        };
+        match result {
+            Ok(Ok(Ok(reply))) => {
+                permit.success();
+                self.health.record_success();
+                Ok(reply)
+            }
+            Ok(Ok(Err(code))) => {
+                //设备有响应，链路是好的
+                permit.success();
+                Err(DeviceError::Exception(code))
+            }
+            Ok(Err(err)) => {
+                //服务器主动关闭与客户端的连接会进入这个异常
+                modbus.status = false;
+                permit.failure();
+                self.health.record_failure(err.to_string());
+                Err(DeviceError::Io(err))
+            }
+            Err(_) => {
+                modbus.status = false;
+                permit.failure();
+                self.health.record_failure(DeviceError::Timeout.to_string());
+                Err(DeviceError::Timeout)
+            }
+        }
+    }
+
+    pub async fn read_holding_registers(
+        &self,
+        priority: Priority,
+        addr: u16,
+        cnt: u16,
+    ) -> Result<Vec<u16>, DeviceError> {
+        match self
+            .call(priority, Request::ReadHoldingRegisters(addr, cnt))
+            .await?
+        {
+            Response::ReadHoldingRegisters(values) => Ok(values),
+            _ => unreachable!("call() should reject mismatching responses"),
+        }
+    }
+}
+
+/// 发给设备的一次请求：解码好的请求，或者透明代理转发的原始PDU
+enum Exchange<'a> {
+    Request(Request<'static>),
+    Raw { unit_id: u8, pdu: &'a [u8] },
+}
+
+enum Reply {
+    Response(Response),
+    Raw(Vec<u8>),
+}
+
+impl Reply {
+    fn into_response(self) -> Response {
+        match self {
+            Reply::Response(response) => response,
+            Reply::Raw(_) => unreachable!("decoded requests get decoded replies"),
+        }
+    }
+
+    fn into_raw(self) -> Vec<u8> {
+        match self {
+            Reply::Raw(pdu) => pdu,
+            Reply::Response(_) => unreachable!("raw exchanges get raw replies"),
+        }
+    }
+}
+
+/// 请求的起始地址和数量，用于span属性
+fn request_range(request: &Request<'_>) -> Option<(u16, u16)> {
+    match request {
+        Request::ReadCoils(addr, cnt)
+        | Request::ReadDiscreteInputs(addr, cnt)
+        | Request::ReadInputRegisters(addr, cnt)
+        | Request::ReadHoldingRegisters(addr, cnt)
+        | Request::ReadWriteMultipleRegisters(addr, cnt, _, _) => Some((*addr, *cnt)),
+        Request::WriteSingleCoil(addr, _)
+        | Request::WriteSingleRegister(addr, _)
+        | Request::MaskWriteRegister(addr, _, _) => Some((*addr, 1)),
+        Request::WriteMultipleCoils(addr, coils) => Some((*addr, coils.len() as u16)),
+        Request::WriteMultipleRegisters(addr, words) => Some((*addr, words.len() as u16)),
+        _ => None,
+    }
+}
+
+/// 数据点、告警的名字会出现在URL和文件路径里，只允许字母、数字、_、-和.，不能以.开头
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+pub fn build_devices(configs: &[app_config::Modbus]) -> Result<Devices, String> {
+    configs
+        .iter()
+        .map(|config| Ok((config.name.clone(), Arc::new(Device::new(config)?))))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{modbus_config, unused_address};
+
+    #[actix_web::test]
+    async fn open_breaker_fails_before_queueing() {
+        let config = modbus_config(&format!(
+            r#"
+            address = "{}"
+            slave_id = 1
+            name = "main"
+            breaker = {{ failure_threshold = 1, cooldown_ms = 60000 }}
+            rate_limit = {{ max_rps = 0.5 }}
+            "#,
+            unused_address()
+        ));
+        let device = Device::new(&config).unwrap();
+
+        let result = device.read_holding_registers(Priority::Control, 0, 1).await;
+        assert!(matches!(result, Err(DeviceError::Pool(_))), "{:?}", result);
+
+        // 限速是每2秒一个请求，断路器打开后不用等限速就失败
+        let started = Instant::now();
+        let result = device.read_holding_registers(Priority::Control, 0, 1).await;
+        assert!(
+            matches!(result, Err(DeviceError::Unavailable { .. })),
+            "{:?}",
+            result
+        );
+        assert!(started.elapsed() < Duration::from_millis(100));
+        assert!(matches!(
+            device.turn(Priority::Control).await,
+            Err(DeviceError::Unavailable { .. })
+        ));
+    }
+}
@@ -0,0 +1,256 @@
+use std::{
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use actix_web::rt::time::interval;
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use tokio_modbus::FunctionCode;
+use tracing::{debug, info, warn};
+
+use crate::{
+    device::{Device, DeviceError},
+    events::{DeviceStateEvent, Event, EventBus},
+    metrics::{device_attrs, METRICS},
+    modbus_manager::check_connection,
+    rate_limiter::Priority,
+};
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceState {
+    Up,
+    Degraded,
+    Down,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct HealthSnapshot {
+    pub state: DeviceState,
+    pub last_success: Option<DateTime<Local>>,
+    pub last_error: Option<String>,
+    pub last_error_at: Option<DateTime<Local>>,
+    pub consecutive_failures: u32,
+}
+
+/// 设备健康状态，由后台探测任务和正常请求共同更新
+pub struct DeviceHealth {
+    device: String,
+    address: String,
+    down_after: u32,
+    inner: Mutex<Inner>,
+    /// 状态变化时发布事件，main里接上事件总线
+    events: OnceLock<Arc<EventBus>>,
+}
+
+struct Inner {
+    snapshot: HealthSnapshot,
+    /// 第一次探测或请求之前状态还没确定，确定状态时不算变化
+    known: bool,
+}
+
+impl DeviceHealth {
+    pub fn new(device: &str, address: &str, down_after: u32) -> Self {
+        DeviceHealth {
+            device: device.to_string(),
+            address: address.to_string(),
+            down_after: down_after.max(1),
+            inner: Mutex::new(Inner {
+                snapshot: HealthSnapshot {
+                    // 还没探测过之前当作不可用
+                    state: DeviceState::Down,
+                    last_success: None,
+                    last_error: None,
+                    last_error_at: None,
+                    consecutive_failures: 0,
+                },
+                known: false,
+            }),
+            events: OnceLock::new(),
+        }
+    }
+
+    pub fn publish_to(&self, events: Arc<EventBus>) {
+        let _ = self.events.set(events);
+    }
+
+    /// 在持有锁的时候发布事件，每次变化都带着真实的旧状态，事件的顺序和状态变化的顺序一致
+    fn transition(&self, inner: &mut Inner, to: DeviceState) {
+        let from = inner.snapshot.state;
+        inner.snapshot.state = to;
+        let known = std::mem::replace(&mut inner.known, true);
+        if !known || from == to {
+            return;
+        }
+        if let Some(events) = self.events.get() {
+            events.publish(Event::DeviceState(DeviceStateEvent {
+                timestamp: Local::now(),
+                device: self.device.clone(),
+                address: self.address.clone(),
+                from,
+                to,
+                last_error: inner.snapshot.last_error.clone(),
+            }));
+        }
+    }
+
+    pub fn record_success(&self) -> DeviceState {
+        let mut inner = self.inner.lock().unwrap();
+        inner.snapshot.last_success = Some(Local::now());
+        inner.snapshot.consecutive_failures = 0;
+        self.transition(&mut inner, DeviceState::Up);
+        inner.snapshot.state
+    }
+
+    pub fn record_failure(&self, error: impl Into<String>) -> DeviceState {
+        let mut inner = self.inner.lock().unwrap();
+        let snapshot = &mut inner.snapshot;
+        snapshot.consecutive_failures += 1;
+        snapshot.last_error = Some(error.into());
+        snapshot.last_error_at = Some(Local::now());
+        let state = if snapshot.consecutive_failures >= self.down_after {
+            DeviceState::Down
+        } else if snapshot.last_success.is_some() {
+            DeviceState::Degraded
+        } else {
+            DeviceState::Down
+        };
+        self.transition(&mut inner, state);
+        inner.snapshot.state
+    }
+
+    pub fn snapshot(&self) -> HealthSnapshot {
+        self.inner.lock().unwrap().snapshot.clone()
+    }
+}
+
+/// 按配置的周期不断探测设备，直到进程退出
+pub async fn probe_loop(device: Arc<Device>) {
+    let mut ticker = interval(Duration::from_millis(device.probe.interval_ms.max(100)));
+    loop {
+        ticker.tick().await;
+        probe_once(&device).await;
+    }
+}
+
+async fn probe_once(device: &Device) {
+    let started = Instant::now();
+    let before = device.health.snapshot().state;
+    // 连接池只有一个连接，如果正被请求占用就跳过这次探测，不算失败
+    let status = device.pool.status();
+    if status.size >= status.max_size && status.available == 0 {
+        debug!("设备{}正忙，跳过本次探测", device.name);
+        return;
+    }
+    // 探测也要遵守设备的限速，断路器打开或者排不上队就跳过
+    let _turn = match device.turn(Priority::Background).await {
+        Ok(turn) => turn,
+        Err(err) => {
+            debug!("设备{}跳过本次探测：{}", device.name, err);
+            return;
+        }
+    };
+    // 探测和普通请求一样经过断路器：断路器打开时跳过，冷却结束后探测就是放行的试探请求，
+    // 成功后断路器关闭，不会出现健康状态是up而请求都被断路器拒绝的情况
+    let after = match device.checkout().await {
+        Err(DeviceError::Unavailable { retry_after, .. }) => {
+            debug!(
+                "设备{}断路器打开，{}ms后再探测",
+                device.name,
+                retry_after.as_millis()
+            );
+            return;
+        }
+        // 连接失败已经在checkout里记入断路器和健康状态
+        Err(err) => {
+            debug!("设备{}探测失败：{}", device.name, err);
+            device.health.snapshot().state
+        }
+        Ok((mut modbus, permit)) => {
+            let request_started = Instant::now();
+            let result = check_connection(
+                &mut modbus.context,
+                device.probe.register,
+                device.probe.timeout_ms,
+            )
+            .await;
+            let outcome = match &result {
+                Ok(()) => "success",
+                Err(err) => err.kind(),
+            };
+            METRICS.record_request(
+                &device.name,
+                FunctionCode::ReadHoldingRegisters,
+                outcome,
+                request_started.elapsed(),
+            );
+            match result {
+                Ok(()) => {
+                    permit.success();
+                    device.health.record_success()
+                }
+                // 设备返回了异常码，链路是通的，和Device::call一样算作可达
+                Err(DeviceError::Exception(code)) => {
+                    debug!(
+                        "设备{}探测寄存器{}返回异常：{}",
+                        device.name, device.probe.register, code
+                    );
+                    permit.success();
+                    device.health.record_success()
+                }
+                Err(err) => {
+                    modbus.status = false;
+                    permit.failure();
+                    debug!("设备{}探测失败：{}", device.name, err);
+                    device.health.record_failure(err.to_string())
+                }
+            }
+        }
+    };
+    METRICS
+        .poll_cycle_duration
+        .record(started.elapsed().as_secs_f64(), &device_attrs(&device.name));
+    if before != after {
+        match after {
+            DeviceState::Up => info!("设备{}({})状态变为{:?}", device.name, device.addr, after),
+            _ => warn!("设备{}({})状态变为{:?}", device.name, device.addr, after),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_state_change_is_published_in_order() {
+        let events = Arc::new(EventBus::default());
+        let mut receiver = events.subscribe_lossless();
+        let health = DeviceHealth::new("main", "127.0.0.1:502", 1);
+        health.publish_to(events);
+
+        // 第一次确定状态不算变化，状态没变也不发
+        health.record_success();
+        health.record_success();
+        // 连续快速变化，每一次都要发出来
+        health.record_failure("超时");
+        health.record_success();
+        health.record_failure("超时");
+        health.record_failure("超时");
+
+        let mut changes = Vec::new();
+        while let Ok(Event::DeviceState(event)) = receiver.try_recv() {
+            assert_eq!(event.device, "main");
+            changes.push((event.from, event.to));
+        }
+        assert_eq!(
+            changes,
+            [
+                (DeviceState::Up, DeviceState::Down),
+                (DeviceState::Down, DeviceState::Up),
+                (DeviceState::Up, DeviceState::Down),
+            ]
+        );
+    }
+}
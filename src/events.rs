@@ -0,0 +1,115 @@
+use std::sync::Mutex;
+
+use actix_web::web::Bytes;
+use chrono::{DateTime, Local};
+use futures_util::{stream, Stream};
+use serde::Serialize;
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    mpsc,
+};
+use tracing::warn;
+
+use crate::{alarms::AlarmEvent, device_health::DeviceState};
+
+/// 订阅者跟不上时最多缓存多少个事件，超过的丢掉
+const CAPACITY: usize = 1024;
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    Alarm(AlarmEvent),
+    DeviceState(DeviceStateEvent),
+}
+
+/// 设备状态变化，由`DeviceHealth`在状态改变时发布，启动后第一次确定状态时不算
+#[derive(Serialize, Clone, Debug)]
+pub struct DeviceStateEvent {
+    pub timestamp: DateTime<Local>,
+    pub device: String,
+    pub address: String,
+    pub from: DeviceState,
+    pub to: DeviceState,
+    pub last_error: Option<String>,
+}
+
+impl Event {
+    /// SSE的event字段，也用于webhook按事件类型过滤
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::Alarm(_) => "alarm",
+            Event::DeviceState(_) => "device_state",
+        }
+    }
+
+    /// 事件所属的设备，按调用方能访问的设备过滤
+    pub fn device(&self) -> &str {
+        match self {
+            Event::Alarm(event) => &event.device,
+            Event::DeviceState(event) => &event.device,
+        }
+    }
+}
+
+/// 进程内的事件广播，没有订阅者时事件直接丢弃
+pub struct EventBus {
+    sender: broadcast::Sender<Event>,
+    /// 不能丢事件的订阅者，例如webhook
+    lossless: Mutex<Vec<mpsc::UnboundedSender<Event>>>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        EventBus {
+            sender: broadcast::channel(CAPACITY).0,
+            lossless: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl EventBus {
+    pub fn publish(&self, event: Event) {
+        self.lossless
+            .lock()
+            .unwrap()
+            .retain(|sender| sender.send(event.clone()).is_ok());
+        let _ = self.sender.send(event);
+    }
+
+    /// 订阅者跟不上时会丢事件，适合SSE这类只关心最新状态的
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+
+    /// 订阅之后发布的每个事件都会收到，处理慢时在内存里排队而不是丢弃
+    pub fn subscribe_lossless(&self) -> mpsc::UnboundedReceiver<Event> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.lossless.lock().unwrap().push(sender);
+        receiver
+    }
+}
+
+/// 把订阅转成Server-Sent Events，`filter`返回false的事件不发
+pub fn sse_stream(
+    receiver: broadcast::Receiver<Event>,
+    filter: impl Fn(&Event) -> bool + 'static,
+) -> impl Stream<Item = Result<Bytes, std::io::Error>> {
+    stream::unfold((receiver, filter), |(mut receiver, filter)| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) if filter(&event) => {
+                    let data = serde_json::to_string(&event).unwrap();
+                    let frame = format!("event: {}\ndata: {}\n\n", event.name(), data);
+                    return Some((Ok(Bytes::from(frame)), (receiver, filter)));
+                }
+                Ok(_) => continue,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("事件订阅者处理太慢，丢弃了{}个事件", skipped);
+                    let frame = format!(": 丢弃了{}个事件\n\n", skipped);
+                    return Some((Ok(Bytes::from(frame)), (receiver, filter)));
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+}
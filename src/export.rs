@@ -0,0 +1,236 @@
+use std::{io, sync::Arc};
+
+use actix_web::{rt::task::spawn_blocking, web::Bytes};
+use chrono::{DateTime, Local};
+use futures_util::{stream, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::{
+    app_config::TagConfig,
+    device::Device,
+    history::History,
+    poller::{self, Quality},
+    rate_limiter::Priority,
+};
+
+/// 导出历史时每次发给客户端多少行
+const CHUNK_ROWS: usize = 256;
+
+const CSV_HEADER: &str = "timestamp,device,tag,register,value,unit,quality\n";
+
+#[derive(Deserialize, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    /// 每行一个JSON对象
+    Jsonl,
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Jsonl => "application/x-ndjson",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Jsonl => "jsonl",
+        }
+    }
+
+    /// CSV有表头，JSON lines没有
+    fn header(&self) -> Option<Bytes> {
+        match self {
+            ExportFormat::Csv => Some(Bytes::from_static(CSV_HEADER.as_bytes())),
+            ExportFormat::Jsonl => None,
+        }
+    }
+}
+
+/// 导出的一行，快照和历史用同样的列
+#[derive(Serialize, Debug)]
+struct Row<'a> {
+    timestamp: Option<DateTime<Local>>,
+    device: &'a str,
+    tag: &'a str,
+    register: u16,
+    value: Option<u16>,
+    unit: Option<&'a str>,
+    quality: Quality,
+}
+
+impl Row<'_> {
+    fn write(&self, format: ExportFormat, out: &mut String) {
+        match format {
+            ExportFormat::Csv => {
+                let fields = [
+                    self.timestamp.map(|t| t.to_rfc3339()).unwrap_or_default(),
+                    csv_field(self.device),
+                    csv_field(self.tag),
+                    self.register.to_string(),
+                    self.value.map(|v| v.to_string()).unwrap_or_default(),
+                    csv_field(self.unit.unwrap_or_default()),
+                    self.quality.as_str().to_string(),
+                ];
+                out.push_str(&fields.join(","));
+            }
+            ExportFormat::Jsonl => out.push_str(&serde_json::to_string(self).unwrap()),
+        }
+        out.push('\n');
+    }
+}
+
+/// 包含逗号、引号或换行的字段加引号，引号写两遍
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// 按轮询的分块实时读设备，读完一块发一块；读失败的块用缓存里的旧值，质量为bad
+pub fn snapshot_stream(
+    device: Arc<Device>,
+    format: ExportFormat,
+) -> impl Stream<Item = Result<Bytes, io::Error>> {
+    let blocks = poller::blocks(&device.tags, device.poll.max_gap);
+    let rows = stream::iter(blocks).then(move |(start, count)| {
+        let device = device.clone();
+        async move {
+            let live = device
+                .read_holding_registers(Priority::Interactive, start, count)
+                .await
+                .ok();
+            let now = Local::now();
+            let mut out = String::new();
+            // 块可能一直到0xFFFF，用u32算结束地址
+            let end = start as u32 + count as u32;
+            for tag in device
+                .tags
+                .iter()
+                .filter(|tag| (start as u32..end).contains(&(tag.register as u32)))
+            {
+                let row = match &live {
+                    Some(values) => Row {
+                        timestamp: Some(now),
+                        value: values.get((tag.register - start) as usize).copied(),
+                        quality: Quality::Good,
+                        ..tag_row(&device.name, tag)
+                    },
+                    None => {
+                        let cached = poller::tag_value(&device, tag);
+                        Row {
+                            timestamp: cached.timestamp,
+                            value: cached.value,
+                            quality: Quality::Bad,
+                            ..tag_row(&device.name, tag)
+                        }
+                    }
+                };
+                row.write(format, &mut out);
+            }
+            Ok(Bytes::from(out))
+        }
+    });
+    stream::iter(format.header().map(Ok)).chain(rows)
+}
+
+fn tag_row<'a>(device: &'a str, tag: &'a TagConfig) -> Row<'a> {
+    Row {
+        timestamp: None,
+        device,
+        tag: &tag.name,
+        register: tag.register,
+        value: None,
+        unit: tag.unit.as_deref(),
+        quality: Quality::Bad,
+    }
+}
+
+/// 在阻塞线程里读历史文件，每CHUNK_ROWS行发一次，客户端断开时停止读
+pub fn history_stream(
+    history: Arc<History>,
+    device: String,
+    tag: TagConfig,
+    from: DateTime<Local>,
+    to: DateTime<Local>,
+    format: ExportFormat,
+) -> impl Stream<Item = Result<Bytes, io::Error>> {
+    let (tx, rx) = mpsc::channel::<Bytes>(4);
+    spawn_blocking(move || {
+        if let Some(header) = format.header() {
+            if tx.blocking_send(header).is_err() {
+                return;
+            }
+        }
+        let mut out = String::new();
+        let mut rows = 0;
+        for point in history.points(&device, &tag.name, from, to) {
+            Row {
+                timestamp: Some(point.t),
+                value: point.v,
+                quality: point.q,
+                ..tag_row(&device, &tag)
+            }
+            .write(format, &mut out);
+            rows += 1;
+            if rows == CHUNK_ROWS {
+                if tx
+                    .blocking_send(Bytes::from(std::mem::take(&mut out)))
+                    .is_err()
+                {
+                    return;
+                }
+                rows = 0;
+            }
+        }
+        if !out.is_empty() {
+            let _ = tx.blocking_send(Bytes::from(out));
+        }
+    });
+    stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (Ok(chunk), rx))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{modbus_config, unused_address};
+
+    #[actix_web::test]
+    async fn snapshot_includes_the_last_register() {
+        // 设备连不上，所有数据点都用缓存，质量为bad
+        let config = modbus_config(&format!(
+            r#"
+            address = "{}"
+            slave_id = 1
+            name = "main"
+            tags = [
+                {{ name = "first", register = 0 }},
+                {{ name = "last", register = 65535 }},
+            ]
+            "#,
+            unused_address()
+        ));
+        let device = Arc::new(Device::new(&config).unwrap());
+
+        let chunks: Vec<_> = snapshot_stream(device, ExportFormat::Csv).collect().await;
+        let csv: String = chunks
+            .into_iter()
+            .map(|chunk| String::from_utf8(chunk.unwrap().to_vec()).unwrap())
+            .collect();
+        let tags: Vec<_> = csv
+            .lines()
+            .skip(1)
+            .map(|line| line.split(',').nth(2).unwrap())
+            .collect();
+        assert_eq!(tags, ["first", "last"], "{}", csv);
+    }
+}
@@ -0,0 +1,298 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Lines, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use actix_web::rt::time::interval;
+use chrono::{DateTime, Days, Local, NaiveDate, TimeDelta};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use crate::{
+    app_config::{HistoryConfig, TagConfig},
+    device::Devices,
+    poller::Quality,
+};
+
+/// 历史文件里的一行，字段名用缩写减小文件体积
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Point {
+    pub t: DateTime<Local>,
+    /// 读失败时为None
+    pub v: Option<u16>,
+    pub q: Quality,
+}
+
+/// 降采样后的一个时间段，只统计quality为good的点
+#[derive(Serialize, Clone, Debug)]
+pub struct Bucket {
+    /// 时间段的开始
+    pub t: DateTime<Local>,
+    pub min: Option<u16>,
+    pub max: Option<u16>,
+    pub avg: Option<f64>,
+    pub count: usize,
+}
+
+/// 每个数据点正在写的文件和上一次存储的点
+struct Writer {
+    day: NaiveDate,
+    file: File,
+    last: Point,
+}
+
+/// 历史数据：`{directory}/{设备}/{数据点}/{日期}.jsonl`，每天一个文件，按天删除过期数据
+pub struct History {
+    directory: PathBuf,
+    retention_days: u32,
+    max_interval: TimeDelta,
+    writers: Mutex<HashMap<(String, String), Writer>>,
+}
+
+impl History {
+    pub fn open(config: &HistoryConfig) -> io::Result<Self> {
+        fs::create_dir_all(&config.directory)?;
+        Ok(History {
+            directory: PathBuf::from(&config.directory),
+            retention_days: config.retention_days,
+            max_interval: TimeDelta::seconds(config.max_interval_secs as i64),
+            writers: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn tag_dir(&self, device: &str, tag: &str) -> PathBuf {
+        self.directory.join(device).join(tag)
+    }
+
+    /// 按死区过滤后追加到当天的文件
+    pub fn record(&self, device: &str, tag: &TagConfig, point: Point) {
+        let key = (device.to_string(), tag.name.clone());
+        let mut writers = self.writers.lock().unwrap();
+        if let Some(writer) = writers.get(&key) {
+            if !changed(&writer.last, &point, tag.deadband)
+                && point.t - writer.last.t < self.max_interval
+            {
+                return;
+            }
+        }
+        let day = point.t.date_naive();
+        if writers.get(&key).is_none_or(|writer| writer.day != day) {
+            let dir = self.tag_dir(device, &tag.name);
+            let opened = fs::create_dir_all(&dir).and_then(|_| {
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(dir.join(format!("{}.jsonl", day)))
+            });
+            match opened {
+                Ok(file) => {
+                    writers.insert(
+                        key.clone(),
+                        Writer {
+                            day,
+                            file,
+                            last: point.clone(),
+                        },
+                    );
+                }
+                Err(err) => {
+                    error!(
+                        "打开设备{}数据点{}的历史文件失败：{}",
+                        device, tag.name, err
+                    );
+                    return;
+                }
+            }
+        }
+        let writer = writers.get_mut(&key).unwrap();
+        let line = serde_json::to_string(&point).unwrap();
+        if let Err(err) = writeln!(writer.file, "{}", line) {
+            error!("写入设备{}数据点{}的历史失败：{}", device, tag.name, err);
+            return;
+        }
+        writer.last = point;
+    }
+
+    /// 时间范围内的原始点，按天逐个文件读，不会一次读进内存
+    pub fn points(
+        &self,
+        device: &str,
+        tag: &str,
+        from: DateTime<Local>,
+        to: DateTime<Local>,
+    ) -> PointIter {
+        let dir = self.tag_dir(device, tag);
+        let files = from
+            .date_naive()
+            .iter_days()
+            .take_while(|day| *day <= to.date_naive())
+            .map(|day| dir.join(format!("{}.jsonl", day)))
+            .filter(|path| path.exists())
+            .collect();
+        PointIter {
+            files,
+            lines: None,
+            from,
+            to,
+        }
+    }
+
+    /// `from`时刻的值，也就是`from`之前最后存的一个点
+    pub fn previous(&self, device: &str, tag: &str, from: DateTime<Local>) -> Option<Point> {
+        let dir = self.tag_dir(device, tag);
+        let mut days: Vec<NaiveDate> = fs::read_dir(&dir)
+            .ok()?
+            .filter_map(|entry| file_day(&entry.ok()?.path()))
+            .filter(|day| *day <= from.date_naive())
+            .collect();
+        days.sort_unstable();
+        days.into_iter().rev().find_map(|day| {
+            let file = File::open(dir.join(format!("{}.jsonl", day))).ok()?;
+            BufReader::new(file)
+                .lines()
+                .map_while(Result::ok)
+                .filter_map(|line| serde_json::from_str::<Point>(&line).ok())
+                .filter(|point| point.t < from)
+                .last()
+        })
+    }
+
+    /// 删掉超过保留天数的文件
+    pub fn purge(&self, devices: &Devices) {
+        let today = Local::now().date_naive();
+        for device in devices.values() {
+            for tag in &device.tags {
+                let days = tag.retention_days.unwrap_or(self.retention_days);
+                let Some(oldest) = today.checked_sub_days(Days::new(days as u64)) else {
+                    continue;
+                };
+                let Ok(entries) = fs::read_dir(self.tag_dir(&device.name, &tag.name)) else {
+                    continue;
+                };
+                for path in entries.filter_map(|entry| Some(entry.ok()?.path())) {
+                    if file_day(&path).is_some_and(|day| day < oldest) {
+                        match fs::remove_file(&path) {
+                            Ok(()) => info!("删除过期的历史文件{}", path.display()),
+                            Err(err) => error!("删除历史文件{}失败：{}", path.display(), err),
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn file_day(path: &Path) -> Option<NaiveDate> {
+    if path.extension()? != "jsonl" {
+        return None;
+    }
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+/// 质量变了或者值变化超过死区
+fn changed(last: &Point, point: &Point, deadband: f64) -> bool {
+    if last.q != point.q {
+        return true;
+    }
+    match (last.v, point.v) {
+        (Some(last), Some(value)) if deadband > 0.0 => {
+            (value as f64 - last as f64).abs() >= deadband
+        }
+        (last, value) => last != value,
+    }
+}
+
+pub struct PointIter {
+    /// 还没读的文件，按日期排序
+    files: Vec<PathBuf>,
+    lines: Option<Lines<BufReader<File>>>,
+    from: DateTime<Local>,
+    to: DateTime<Local>,
+}
+
+impl Iterator for PointIter {
+    type Item = Point;
+
+    fn next(&mut self) -> Option<Point> {
+        loop {
+            if let Some(lines) = &mut self.lines {
+                for line in lines.map_while(Result::ok) {
+                    // 进程异常退出时最后一行可能不完整，跳过
+                    let Ok(point) = serde_json::from_str::<Point>(&line) else {
+                        continue;
+                    };
+                    if point.t > self.to {
+                        self.files.clear();
+                        break;
+                    }
+                    if point.t >= self.from {
+                        return Some(point);
+                    }
+                }
+                self.lines = None;
+            }
+            if self.files.is_empty() {
+                return None;
+            }
+            let path = self.files.remove(0);
+            match File::open(&path) {
+                Ok(file) => self.lines = Some(BufReader::new(file).lines()),
+                Err(err) => error!("打开历史文件{}失败：{}", path.display(), err),
+            }
+        }
+    }
+}
+
+/// 按`step`把点分到从`from`开始的时间段里，没有点的时间段也返回，count为0
+pub fn downsample(
+    points: impl Iterator<Item = Point>,
+    from: DateTime<Local>,
+    to: DateTime<Local>,
+    step: TimeDelta,
+) -> Vec<Bucket> {
+    let mut buckets = Vec::new();
+    let mut start = from;
+    while start < to {
+        buckets.push(Bucket {
+            t: start,
+            min: None,
+            max: None,
+            avg: None,
+            count: 0,
+        });
+        start += step;
+    }
+    let mut sums = vec![0f64; buckets.len()];
+    for point in points {
+        let (Some(value), Quality::Good) = (point.v, point.q) else {
+            continue;
+        };
+        let index = ((point.t - from).num_milliseconds() / step.num_milliseconds()) as usize;
+        let Some(bucket) = buckets.get_mut(index) else {
+            continue;
+        };
+        bucket.min = Some(bucket.min.map_or(value, |min| min.min(value)));
+        bucket.max = Some(bucket.max.map_or(value, |max| max.max(value)));
+        bucket.count += 1;
+        sums[index] += value as f64;
+    }
+    for (bucket, sum) in buckets.iter_mut().zip(sums) {
+        if bucket.count > 0 {
+            bucket.avg = Some(sum / bucket.count as f64);
+        }
+    }
+    buckets
+}
+
+/// 每小时清理一次过期的历史
+pub async fn purge_loop(history: Arc<History>, devices: Devices) {
+    let mut ticker = interval(Duration::from_secs(3600));
+    loop {
+        ticker.tick().await;
+        history.purge(&devices);
+    }
+}
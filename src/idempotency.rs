@@ -0,0 +1,168 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    body::{self, BoxBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::{
+        header::{self, HeaderName, HeaderValue},
+        StatusCode,
+    },
+    middleware::Next,
+    web, Error, HttpMessage, HttpResponse,
+};
+use tokio::sync::OnceCell;
+use tracing::{debug, info};
+
+use crate::app_config::IdempotencyConfig;
+use crate::auth::Identity;
+use crate::server_router::Response;
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+/// 返回的是缓存的结果时带上这个响应头
+pub const REPLAYED_HEADER: &str = "idempotent-replayed";
+
+/// 第一次请求的响应
+struct Stored {
+    /// 请求方法、路径和body的摘要，相同key用在不同请求上时拒绝
+    fingerprint: u64,
+    status: StatusCode,
+    content_type: Option<HeaderValue>,
+    body: web::Bytes,
+}
+
+struct Entry {
+    expires: Instant,
+    /// 同一个key并发的请求共用一个cell，只有一个会真正执行，其他的等它的结果
+    cell: Arc<OnceCell<Stored>>,
+}
+
+/// 按(调用方, Idempotency-Key)缓存写接口的响应
+pub struct IdempotencyCache {
+    window: Duration,
+    entries: Mutex<HashMap<(String, String), Entry>>,
+}
+
+impl IdempotencyCache {
+    pub fn new(config: &IdempotencyConfig) -> Self {
+        IdempotencyCache {
+            window: Duration::from_secs(config.window_secs),
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn cell(&self, identity: &str, key: &str) -> Arc<OnceCell<Stored>> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| entry.expires > now);
+        entries
+            .entry((identity.to_string(), key.to_string()))
+            .or_insert_with(|| Entry {
+                expires: now + self.window,
+                cell: Arc::new(OnceCell::new()),
+            })
+            .cell
+            .clone()
+    }
+}
+
+/// 没有真正发给设备的结果不缓存，重试时应该重新执行
+fn cacheable(status: StatusCode) -> bool {
+    !matches!(
+        status,
+        StatusCode::UNAUTHORIZED | StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+    )
+}
+
+enum NotStored {
+    Uncacheable(ServiceResponse<BoxBody>),
+    Failed(Error),
+}
+
+/// 只加在写接口上：`#[post("...", wrap = "from_fn(idempotency_middleware)")]`
+pub async fn idempotency_middleware(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let key = req
+        .headers()
+        .get(IDEMPOTENCY_KEY_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let identity = req.extensions().get::<Identity>().map(|id| id.name.clone());
+    let cache = req.app_data::<web::Data<IdempotencyCache>>().cloned();
+    let (Some(key), Some(identity), Some(cache)) = (key, identity, cache) else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+
+    let body = req.extract::<web::Bytes>().await?;
+    let mut hasher = DefaultHasher::new();
+    req.method().as_str().hash(&mut hasher);
+    req.path().hash(&mut hasher);
+    body.hash(&mut hasher);
+    let fingerprint = hasher.finish();
+    req.set_payload(body.into());
+
+    let http_req = req.request().clone();
+    let cell = cache.cell(&identity, &key);
+    let mut replayed = true;
+    let stored = cell
+        .get_or_try_init(|| async {
+            replayed = false;
+            let res = next.call(req).await.map_err(NotStored::Failed)?;
+            let status = res.status();
+            if !cacheable(status) {
+                return Err(NotStored::Uncacheable(res.map_into_boxed_body()));
+            }
+            let content_type = res.headers().get(header::CONTENT_TYPE).cloned();
+            let body = body::to_bytes(res.into_body()).await.map_err(|_| {
+                NotStored::Failed(actix_web::error::ErrorInternalServerError("读取响应失败"))
+            })?;
+            Ok(Stored {
+                fingerprint,
+                status,
+                content_type,
+                body,
+            })
+        })
+        .await;
+    let stored = match stored {
+        Ok(stored) => stored,
+        Err(NotStored::Uncacheable(res)) => return Ok(res),
+        Err(NotStored::Failed(err)) => return Err(err),
+    };
+    if stored.fingerprint != fingerprint {
+        return Ok(ServiceResponse::new(
+            http_req,
+            HttpResponse::UnprocessableEntity()
+                .json(Response::error(format!(
+                    "Idempotency-Key {}已经用于另一个请求",
+                    key
+                )))
+                .map_into_boxed_body(),
+        ));
+    }
+    if replayed {
+        info!("Idempotency-Key {}重复请求，返回缓存的结果", key);
+    } else {
+        debug!("缓存Idempotency-Key {}的结果：{}", key, stored.status);
+    }
+    let mut response = HttpResponse::build(stored.status);
+    if let Some(content_type) = &stored.content_type {
+        response.insert_header((header::CONTENT_TYPE, content_type.clone()));
+    }
+    if replayed {
+        response.insert_header((
+            HeaderName::from_static(REPLAYED_HEADER),
+            HeaderValue::from_static("true"),
+        ));
+    }
+    Ok(ServiceResponse::new(
+        http_req,
+        response.body(stored.body.clone()).map_into_boxed_body(),
+    ))
+}
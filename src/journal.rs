@@ -0,0 +1,161 @@
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::Path,
+    sync::{mpsc, Mutex},
+    thread::{self, JoinHandle},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+use tracing::error;
+
+/// 追加写入的JSON lines文件，同时在内存里保留最近的记录供查询；审计日志和告警日志都用它。
+/// 文件由单独的线程按顺序写入，记录时不会在异步任务里阻塞
+pub struct Journal<T> {
+    /// 日志的名字，用于错误日志，如"审计日志"
+    kind: &'static str,
+    /// 发给写文件线程的行，Drop时先关闭再等线程写完
+    lines: Option<mpsc::Sender<String>>,
+    writer: Option<JoinHandle<()>>,
+    recent: Mutex<VecDeque<T>>,
+    capacity: usize,
+}
+
+impl<T: Serialize + DeserializeOwned + Clone> Journal<T> {
+    /// 打开`{directory}/{file_name}`，把文件末尾最多`memory_entries`条记录读进内存
+    pub fn open(
+        kind: &'static str,
+        directory: &str,
+        file_name: &str,
+        memory_entries: usize,
+    ) -> io::Result<Self> {
+        fs::create_dir_all(directory)?;
+        let path = Path::new(directory).join(file_name);
+        let capacity = memory_entries.max(1);
+        let recent = load_recent(&path, capacity)?;
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let (lines, receiver) = mpsc::channel();
+        let writer = thread::Builder::new()
+            .name(format!("journal-{}", file_name))
+            .spawn(move || write_lines(kind, file, receiver))?;
+        Ok(Journal {
+            kind,
+            lines: Some(lines),
+            writer: Some(writer),
+            recent: Mutex::new(recent),
+            capacity,
+        })
+    }
+
+    pub fn record(&self, record: T) {
+        match serde_json::to_string(&record) {
+            Ok(line) => {
+                if let Some(lines) = &self.lines {
+                    // 写文件线程只会在Drop之后退出
+                    let _ = lines.send(line);
+                }
+            }
+            Err(err) => error!("序列化{}记录失败：{}", self.kind, err),
+        }
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() >= self.capacity {
+            recent.pop_front();
+        }
+        recent.push_back(record);
+    }
+
+    /// 最近的n条记录，最新的在前
+    pub fn recent(&self, n: usize) -> Vec<T> {
+        self.recent_matching(n, |_| true)
+    }
+
+    /// 满足条件的最近n条记录，最新的在前；先过滤再取n条
+    pub fn recent_matching(&self, n: usize, filter: impl Fn(&T) -> bool) -> Vec<T> {
+        self.recent
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .filter(|record| filter(record))
+            .take(n)
+            .cloned()
+            .collect()
+    }
+}
+
+impl<T> Drop for Journal<T> {
+    fn drop(&mut self) {
+        // 关闭通道后等写文件线程把剩下的行写完
+        self.lines.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+fn write_lines(kind: &str, mut file: File, lines: mpsc::Receiver<String>) {
+    for line in lines {
+        if let Err(err) = writeln!(file, "{}", line).and_then(|_| file.flush()) {
+            // 记录写不进去也不能影响已经发生的操作，只能记错误日志
+            error!("写入{}失败：{}，记录：{}", kind, err, line);
+        }
+    }
+}
+
+fn load_recent<T: DeserializeOwned>(path: &Path, capacity: usize) -> io::Result<VecDeque<T>> {
+    let mut recent = VecDeque::with_capacity(capacity);
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(recent),
+        Err(err) => return Err(err),
+    };
+    for line in BufReader::new(file).lines() {
+        // 最后一行可能因为异常退出只写了一半，跳过解析不了的行
+        if let Ok(record) = serde_json::from_str::<T>(&line?) {
+            if recent.len() >= capacity {
+                recent.pop_front();
+            }
+            recent.push_back(record);
+        }
+    }
+    Ok(recent)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::test_support::temp_dir;
+
+    #[test]
+    fn filters_before_taking_the_limit() {
+        let journal =
+            Journal::<Value>::open("测试日志", &temp_dir("journal-filter"), "j.jsonl", 10).unwrap();
+        for (index, device) in ["a", "a", "b", "b", "b"].iter().enumerate() {
+            journal.record(json!({ "device": device, "index": index }));
+        }
+        let records = journal.recent_matching(2, |record| record["device"] == "a");
+        assert_eq!(
+            records,
+            [
+                json!({ "device": "a", "index": 1 }),
+                json!({ "device": "a", "index": 0 })
+            ]
+        );
+    }
+
+    #[test]
+    fn records_are_written_in_order_before_drop() {
+        let directory = temp_dir("journal-reopen");
+        let journal = Journal::<Value>::open("测试日志", &directory, "j.jsonl", 10).unwrap();
+        for index in 0..100 {
+            journal.record(json!(index));
+        }
+        drop(journal);
+        let journal = Journal::<Value>::open("测试日志", &directory, "j.jsonl", 100).unwrap();
+        let expected: Vec<_> = (0..100).rev().map(|index| json!(index)).collect();
+        assert_eq!(journal.recent(100), expected);
+    }
+}
@@ -0,0 +1,257 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use actix_web::rt::time::sleep;
+use chrono::{DateTime, Local};
+use opentelemetry::global;
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_appender_tracing::layer::OpenTelemetryTracingBridge;
+use opentelemetry_sdk::logs::LoggerProvider;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use serde::Serialize;
+use tracing::{info, warn};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{fmt, reload, EnvFilter, Layer, Registry};
+
+use crate::app_config::{LogFormat, LogRotation, LogTarget, LoggingConfig, TelemetryConfig};
+use crate::otlp::{init_logs, init_traces};
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// 日志初始化的结果，guard要一直持有到程序退出，否则文件日志会丢
+pub struct LogGuard {
+    pub logger_provider: Option<LoggerProvider>,
+    pub log_level: Arc<LogLevel>,
+    _file_guard: Option<WorkerGuard>,
+}
+
+/// OTLP导出自己也会打日志，这些crate必须关掉，不然日志会循环导出
+fn env_filter(filter: EnvFilter) -> EnvFilter {
+    filter
+        .add_directive("hyper=off".parse().unwrap())
+        .add_directive("opentelemetry=off".parse().unwrap())
+        .add_directive("tonic=off".parse().unwrap())
+        .add_directive("h2=off".parse().unwrap())
+        .add_directive("reqwest=off".parse().unwrap())
+        .add_directive("tower=off".parse().unwrap())
+}
+
+/// 启动时的日志级别：`RUST_LOG`优先，其次是配置里的`filter`
+fn startup_directives(config: &LoggingConfig) -> String {
+    match std::env::var(EnvFilter::DEFAULT_ENV) {
+        Ok(directives) if !directives.trim().is_empty() => directives,
+        _ => config.filter.clone(),
+    }
+}
+
+fn parse_filter(directives: &str) -> Result<EnvFilter, String> {
+    EnvFilter::try_new(directives)
+        .map(env_filter)
+        .map_err(|err| format!("无效的日志过滤指令{}：{}", directives, err))
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct LogLevelSnapshot {
+    /// 当前生效的过滤指令，比如"info,modbus::device=trace"
+    pub directives: String,
+    /// 启动时的过滤指令，临时修改到期后恢复成它
+    pub default: String,
+    pub revert_at: Option<DateTime<Local>>,
+}
+
+struct LogLevelState {
+    directives: String,
+    revert_at: Option<DateTime<Local>>,
+    /// 每次修改加一，过期的自动恢复任务发现版本变了就不再恢复
+    generation: u64,
+}
+
+/// 运行时修改日志级别，不用重启就能打开某个设备的debug日志
+pub struct LogLevel {
+    handle: reload::Handle<EnvFilter, Registry>,
+    default: String,
+    state: Mutex<LogLevelState>,
+}
+
+impl LogLevel {
+    pub fn snapshot(&self) -> LogLevelSnapshot {
+        let state = self.state.lock().unwrap();
+        LogLevelSnapshot {
+            directives: state.directives.clone(),
+            default: self.default.clone(),
+            revert_at: state.revert_at,
+        }
+    }
+
+    /// 替换过滤指令，`duration`到期后自动恢复成启动时的配置
+    pub fn set(
+        self: &Arc<Self>,
+        directives: &str,
+        duration: Option<Duration>,
+    ) -> Result<LogLevelSnapshot, String> {
+        let filter = parse_filter(directives)?;
+        let generation = {
+            let mut state = self.state.lock().unwrap();
+            self.handle
+                .reload(filter)
+                .map_err(|err| format!("修改日志级别失败：{}", err))?;
+            state.directives = directives.to_string();
+            state.revert_at = duration
+                .and_then(|duration| chrono::Duration::from_std(duration).ok())
+                .map(|duration| Local::now() + duration);
+            state.generation += 1;
+            state.generation
+        };
+        warn!(
+            "日志过滤指令修改为{}，{}",
+            directives,
+            match duration {
+                Some(duration) => format!("{}秒后恢复", duration.as_secs()),
+                None => "不会自动恢复".to_string(),
+            }
+        );
+        if let Some(duration) = duration {
+            let log_level = self.clone();
+            actix_web::rt::spawn(async move {
+                sleep(duration).await;
+                log_level.revert_if(generation);
+            });
+        }
+        Ok(self.snapshot())
+    }
+
+    pub fn reset(&self) -> LogLevelSnapshot {
+        let generation = self.state.lock().unwrap().generation;
+        self.revert_if(generation);
+        self.snapshot()
+    }
+
+    fn revert_if(&self, generation: u64) {
+        let mut state = self.state.lock().unwrap();
+        if state.generation != generation {
+            return;
+        }
+        // 启动时已经校验过，这里不会失败
+        let filter = parse_filter(&self.default).unwrap();
+        if let Err(err) = self.handle.reload(filter) {
+            warn!("恢复日志级别失败：{}", err);
+            return;
+        }
+        state.directives = self.default.clone();
+        state.revert_at = None;
+        state.generation += 1;
+        info!("日志过滤指令恢复为{}", self.default);
+    }
+}
+
+fn format_layer<W>(format: LogFormat, service_name: &str, writer: W, ansi: bool) -> BoxedLayer
+where
+    W: for<'a> fmt::MakeWriter<'a> + Send + Sync + 'static,
+{
+    match format {
+        LogFormat::Pretty => fmt::layer()
+            .pretty()
+            .with_thread_names(true)
+            .with_ansi(ansi)
+            .with_writer(writer)
+            .boxed(),
+        LogFormat::Compact => fmt::layer()
+            .compact()
+            .with_thread_names(true)
+            .with_ansi(ansi)
+            .with_writer(writer)
+            .boxed(),
+        LogFormat::Json => fmt::layer()
+            .json()
+            .with_thread_names(true)
+            .with_current_span(true)
+            .with_writer(writer)
+            .boxed(),
+        LogFormat::Bunyan => JsonStorageLayer
+            .and_then(BunyanFormattingLayer::new(service_name.to_string(), writer))
+            .boxed(),
+    }
+}
+
+fn rolling_file(config: &LoggingConfig) -> Result<RollingFileAppender, String> {
+    let rotation = match config.rotation {
+        LogRotation::Minutely => Rotation::MINUTELY,
+        LogRotation::Hourly => Rotation::HOURLY,
+        LogRotation::Daily => Rotation::DAILY,
+        LogRotation::Never => Rotation::NEVER,
+    };
+    let mut builder = RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix(&config.file_name);
+    if let Some(max_files) = config.max_files {
+        builder = builder.max_log_files(max_files);
+    }
+    builder
+        .build(&config.directory)
+        .map_err(|err| format!("创建日志文件失败：{}", err))
+}
+
+pub fn init_log(config: &LoggingConfig, telemetry: &TelemetryConfig) -> Result<LogGuard, String> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    // 没有配置OTLP时用一个不导出的provider，span仍然有trace id，日志和响应头里可以用
+    let tracer_provider = init_traces(telemetry)
+        .map_err(|err| format!("初始化OTLP trace失败：{}", err))?
+        .unwrap_or_default();
+    let tracer = tracer_provider.tracer(telemetry.service_name.clone());
+    global::set_tracer_provider(tracer_provider);
+    // trace不受日志级别影响，调低日志级别时仍然能看到请求的trace
+    let trace_layer = tracing_opentelemetry::layer()
+        .with_tracer(tracer)
+        .with_filter(env_filter(EnvFilter::new("info")))
+        .boxed();
+
+    let logger_provider =
+        init_logs(telemetry).map_err(|err| format!("初始化OTLP日志失败：{}", err))?;
+    let mut outputs: Vec<BoxedLayer> = Vec::new();
+    if let Some(logger_provider) = &logger_provider {
+        outputs.push(OpenTelemetryTracingBridge::new(logger_provider).boxed());
+    }
+    if config.targets.contains(&LogTarget::Stdout) {
+        outputs.push(format_layer(
+            config.format,
+            &telemetry.service_name,
+            std::io::stdout,
+            true,
+        ));
+    }
+    let mut file_guard = None;
+    if config.targets.contains(&LogTarget::File) {
+        let (non_blocking, guard) = tracing_appender::non_blocking(rolling_file(config)?);
+        file_guard = Some(guard);
+        outputs.push(format_layer(
+            config.format,
+            &telemetry.service_name,
+            non_blocking,
+            false,
+        ));
+    }
+
+    let directives = startup_directives(config);
+    let (filter, handle) = reload::Layer::new(parse_filter(&directives)?);
+    let outputs = outputs.with_filter(filter).boxed();
+    tracing_subscriber::registry()
+        .with(vec![trace_layer, outputs])
+        .try_init()
+        .map_err(|err| format!("初始化日志失败：{}", err))?;
+    Ok(LogGuard {
+        logger_provider,
+        log_level: Arc::new(LogLevel {
+            handle,
+            default: directives.clone(),
+            state: Mutex::new(LogLevelState {
+                directives,
+                revert_at: None,
+                generation: 0,
+            }),
+        }),
+        _file_guard: file_guard,
+    })
+}
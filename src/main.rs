@@ -1,36 +1,64 @@
+mod alarms;
 mod app_config;
+mod audit;
+mod auth;
+mod circuit_breaker;
+mod commands;
+mod device;
+mod device_health;
+mod events;
+mod export;
+mod history;
+mod idempotency;
+mod journal;
+mod logging;
+mod metrics;
 mod modbus_manager;
+mod modbus_proxy;
+mod modbus_server;
+mod modbus_tls;
+mod mqtt;
 mod otlp;
+mod poller;
+mod rate_limiter;
+mod select_operate;
 mod server_router;
+/// 测试共用的模拟设备、临时目录和配置
+#[cfg(test)]
+mod test_support;
+mod tls;
 mod trace_middleware;
-use actix_web::dev::Service;
+mod webhooks;
+mod write_policy;
 use actix_web::middleware::from_fn;
 use actix_web::{middleware, web, App, HttpServer};
+use alarms::AlarmEngine;
 use app_config::{load_config, AppConfig};
-use futures_util::FutureExt as _;
-use modbus_manager::{ModbusManager, Pool};
-use opentelemetry::global::{self, ObjectSafeTracerProvider};
-use opentelemetry::trace::TracerProvider;
-use opentelemetry::KeyValue;
-use opentelemetry_appender_tracing::layer::OpenTelemetryTracingBridge;
-use opentelemetry_otlp::WithExportConfig;
-use opentelemetry_sdk::logs::LoggerProvider;
-use opentelemetry_sdk::{
-    propagation::TraceContextPropagator, runtime::TokioCurrentThread, trace::Config, Resource,
+use auth::{auth_middleware, Authenticator};
+use commands::RouteTable;
+use device::{build_devices, Devices};
+use events::EventBus;
+use history::History;
+use idempotency::IdempotencyCache;
+use logging::init_log;
+use metrics::{init_meter_provider, register_device_gauges, PrometheusReader};
+use modbus_proxy::ModbusProxy;
+use modbus_server::ModbusServer;
+use mqtt::MqttBridge;
+use opentelemetry::global;
+use otlp::{init_metrics, resource};
+use select_operate::SelectBeforeOperate;
+use server_router::{
+    ack_alarm, export_history, export_snapshot, get_alarm_journal, get_alarms, get_audit,
+    get_devices, get_events, get_history, get_log_level, get_metrics, get_modbus_value, greet,
+    operate_route, reset_log_level, select_route, set_log_level, switch_route,
+    write_modbus_registers,
 };
-use opentelemetry_semantic_conventions::resource;
-use otlp::{init_logs, init_traces, SERVICE_NAME};
-use server_router::{get_modbus_value, greet};
-use std::fmt::Error;
-use std::{collections::HashMap, sync::LazyLock};
+use std::sync::{Arc, LazyLock};
+use tokio::net::TcpListener;
 use trace_middleware::trace_middleware;
-use tracing::{debug, info};
-use tracing_actix_web::TracingLogger;
-use tracing_appender::non_blocking::WorkerGuard;
-use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer};
-use tracing_subscriber::fmt;
-use tracing_subscriber::prelude::*;
-use tracing_subscriber::{layer::SubscriberExt, EnvFilter, Registry};
+use tracing::{debug, error, info};
+use webhooks::Webhooks;
 
 static APP_CONFIG: LazyLock<AppConfig> = LazyLock::new(|| {
     let config = load_config().unwrap();
@@ -38,93 +66,145 @@ static APP_CONFIG: LazyLock<AppConfig> = LazyLock::new(|| {
     config
 });
 
-fn env_filter(filter: EnvFilter) -> EnvFilter {
-    filter
-        .add_directive("opentelemetry=debug".parse().unwrap())
-        .add_directive("hyper=off".parse().unwrap())
-        .add_directive("opentelemetry=off".parse().unwrap())
-        .add_directive("tonic=off".parse().unwrap())
-        .add_directive("h2=off".parse().unwrap())
-        .add_directive("reqwest=off".parse().unwrap())
-        .add_directive("tower=off".parse().unwrap())
-}
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let (logger_provider, _guard) = init_log().unwrap();
-    let pools: HashMap<String, Pool> = APP_CONFIG
-        .modbus
-        .configs
-        .iter()
-        .map(|config| {
-            let mgr = ModbusManager {
-                addr: config.address.to_string(),
-                slave: config.slave_id,
-            };
-            let modbus_pool = Pool::builder(mgr).max_size(1).build().unwrap();
-            (config.name.clone(), modbus_pool)
-        })
-        .collect();
-    let server_url = &*APP_CONFIG.server.address;
+    let log_guard = init_log(&APP_CONFIG.logging, &APP_CONFIG.telemetry).unwrap();
+    let telemetry = &APP_CONFIG.telemetry;
+    let prometheus = PrometheusReader::new();
+    let otlp_metrics = init_metrics(telemetry).unwrap();
+    let meter_provider = init_meter_provider(&prometheus, otlp_metrics, resource(telemetry));
+    let devices: Devices = build_devices(&APP_CONFIG.modbus.configs).unwrap();
+    register_device_gauges(&global::meter("modbus"), &devices);
+    let history = web::Data::new(History::open(&APP_CONFIG.history)?);
+    let events = web::Data::new(EventBus::default());
+    // 先订阅再启动告警、探测和轮询任务，webhook不会漏掉启动时的事件
+    let webhooks = Arc::new(Webhooks::new(&APP_CONFIG.webhooks).unwrap());
+    webhooks::spawn_delivery_loops(&webhooks);
+    actix_web::rt::spawn(webhooks::dispatch_loop(
+        webhooks,
+        events.subscribe_lossless(),
+    ));
+    let alarms = web::Data::new(
+        AlarmEngine::new(
+            &APP_CONFIG.modbus.configs,
+            &devices,
+            &APP_CONFIG.alarm_journal,
+            events.clone().into_inner(),
+        )
+        .unwrap(),
+    );
+    actix_web::rt::spawn(alarms::alarm_loop(alarms.clone().into_inner()));
+    for device in devices.values() {
+        // 探测和轮询开始之前接上事件总线，状态变化都会发布
+        device.health.publish_to(events.clone().into_inner());
+        actix_web::rt::spawn(device_health::probe_loop(device.clone()));
+        if !device.tags.is_empty() {
+            actix_web::rt::spawn(poller::poll_loop(
+                device.clone(),
+                history.clone().into_inner(),
+            ));
+        }
+    }
+    actix_web::rt::spawn(history::purge_loop(
+        history.clone().into_inner(),
+        devices.clone(),
+    ));
+    let log_level = web::Data::from(log_guard.log_level.clone());
+    let authenticator = web::Data::new(Authenticator::new(&APP_CONFIG.auth).unwrap());
+    let audit = web::Data::new(audit::open(&APP_CONFIG.audit)?);
+    if let Some(config) = &APP_CONFIG.mqtt {
+        let (bridge, event_loop) =
+            MqttBridge::new(config, devices.clone(), audit.clone().into_inner()).unwrap();
+        let bridge = Arc::new(bridge);
+        actix_web::rt::spawn(mqtt::connection_loop(bridge.clone(), event_loop));
+        actix_web::rt::spawn(mqtt::publish_loop(bridge));
+    }
+    if let Some(config) = &APP_CONFIG.modbus_server {
+        let server = ModbusServer::new(config, &devices, audit.clone().into_inner()).unwrap();
+        let listener = TcpListener::bind(&config.listen).await?;
+        info!("Modbus服务端监听{}", config.listen);
+        actix_web::rt::spawn(modbus_server::serve(Arc::new(server), listener));
+    }
+    for config in &APP_CONFIG.modbus.configs {
+        let Some(proxy_config) = &config.proxy else {
+            continue;
+        };
+        let listener = TcpListener::bind(&proxy_config.listen).await?;
+        info!("设备{}的透明代理监听{}", config.name, proxy_config.listen);
+        let proxy = ModbusProxy::new(
+            devices[&config.name].clone(),
+            proxy_config.clone(),
+            audit.clone().into_inner(),
+        );
+        actix_web::rt::spawn(modbus_proxy::serve(Arc::new(proxy), listener));
+    }
+    let routes = web::Data::new(RouteTable::new(&APP_CONFIG.routes));
+    let idempotency = web::Data::new(IdempotencyCache::new(&APP_CONFIG.idempotency));
+    let sbo = web::Data::new(SelectBeforeOperate::new(&APP_CONFIG.select_before_operate));
     info!(name: "my-event", target: "my-target", "hello from {}. My price is {}", "apple", 1.99);
-    let tracer_provider = init_traces().unwrap();
-    HttpServer::new(move || {
+    let mut server = HttpServer::new(move || {
         App::new()
+            .wrap(from_fn(auth_middleware))
             .wrap(middleware::Logger::default())
-            // .wrap(from_fn(trace_middleware))
-            .wrap(TracingLogger::default())
-            .app_data(web::Data::new(pools.clone()))
+            .wrap(from_fn(trace_middleware))
+            .app_data(web::Data::new(devices.clone()))
+            .app_data(web::Data::new(prometheus.clone()))
+            .app_data(audit.clone())
+            .app_data(history.clone())
+            .app_data(events.clone())
+            .app_data(alarms.clone())
+            .app_data(routes.clone())
+            .app_data(sbo.clone())
+            .app_data(idempotency.clone())
+            .app_data(log_level.clone())
+            .app_data(authenticator.clone())
             .service(greet)
             .service(get_modbus_value)
-    })
-    .bind(server_url)?
-    .run()
-    .await?;
-    // global::set_tracer_provider(tracer_provider);
-    logger_provider.shutdown().unwrap();
+            .service(get_devices)
+            .service(get_metrics)
+            .service(write_modbus_registers)
+            .service(switch_route)
+            .service(select_route)
+            .service(operate_route)
+            .service(get_audit)
+            .service(get_history)
+            .service(export_snapshot)
+            .service(export_history)
+            .service(get_alarms)
+            .service(get_alarm_journal)
+            .service(ack_alarm)
+            .service(get_events)
+            .service(get_log_level)
+            .service(set_log_level)
+            .service(reset_log_level)
+    });
+    let server_config = &APP_CONFIG.server;
+    if server_config.address.is_none() && server_config.tls.is_none() {
+        return Err(std::io::Error::other(
+            "server.address和server.tls至少要配置一个",
+        ));
+    }
+    if let Some(address) = &server_config.address {
+        server = server.bind(address)?;
+    }
+    if let Some(tls_config) = &server_config.tls {
+        let (rustls_config, reloader) =
+            tls::server_config(tls_config).map_err(std::io::Error::other)?;
+        actix_web::rt::spawn(tls::reload_loop(reloader));
+        server = server.bind_rustls_0_23(&tls_config.address, rustls_config)?;
+        info!("HTTPS监听{}", tls_config.address);
+    }
+    server.run().await?;
+    // collector不可用时这里会返回导出失败，不影响退出
+    if let Some(logger_provider) = &log_guard.logger_provider {
+        if let Err(err) = logger_provider.shutdown() {
+            error!("关闭OTLP日志导出失败：{:?}", err);
+        }
+    }
+    if let Err(err) = meter_provider.shutdown() {
+        error!("关闭指标导出失败：{:?}", err);
+    }
 
     opentelemetry::global::shutdown_tracer_provider();
     Ok(())
 }
-const APP_NAME: &str = "tracing-actix-web-demo";
-fn init_log() -> Result<(opentelemetry_sdk::logs::LoggerProvider, WorkerGuard), String> {
-    global::set_text_map_propagator(TraceContextPropagator::new());
-    let tracer = init_traces().unwrap().tracer(APP_NAME);
-    // global::set_tracer_provider(init_traces().unwrap());
-    // let env_filter = EnvFilter::try_from_default_env().unwrap_or(EnvFilter::new("info"));
-    let telemetry = tracing_opentelemetry::layer().with_tracer(tracer);
-    let formatting_layer = BunyanFormattingLayer::new(APP_NAME.into(), std::io::stdout);
-
-    // let subscriber = Registry::default()
-    //     .with(env_filter)
-    //     .with(telemetry)
-    //     .with(JsonStorageLayer)
-    //     .with(formatting_layer);
-
-    let logger_provider: opentelemetry_sdk::logs::LoggerProvider = init_logs().unwrap();
-    let otel_layer = OpenTelemetryTracingBridge::new(&logger_provider)
-        .with_filter(env_filter(EnvFilter::new("debug")));
-
-    let fmt_layer = tracing_subscriber::fmt::layer()
-        .with_thread_names(true)
-        .with_filter(env_filter(EnvFilter::new("debug")));
-
-    let file_appender = tracing_appender::rolling::daily("logs", "app.log");
-    let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
-    // 创建一个文件输出层
-    let file_layer = fmt::layer()
-        .with_thread_names(true)
-        .with_ansi(false)
-        .with_writer(non_blocking)
-        .with_filter(env_filter(EnvFilter::new("debug")));
-
-    tracing_subscriber::registry()
-        .with(otel_layer)
-        .with(fmt_layer)
-        .with(file_layer)
-        .with(env_filter(EnvFilter::new("debug")))
-        .with(telemetry)
-        .with(JsonStorageLayer)
-        .with(formatting_layer)
-        .init();
-    Ok((logger_provider, _guard))
-}
@@ -0,0 +1,520 @@
+use std::{
+    fmt::Write,
+    sync::{Arc, LazyLock, Weak},
+    time::Duration,
+};
+
+use opentelemetry::{
+    global,
+    metrics::{Counter, Histogram, Meter},
+    KeyValue, Value,
+};
+use opentelemetry_sdk::metrics::{
+    data::{self, ResourceMetrics},
+    reader::MetricReader,
+    InstrumentKind, ManualReader, MeterProviderBuilder, MetricResult, PeriodicReader, Pipeline,
+    SdkMeterProvider, Temporality,
+};
+use opentelemetry_sdk::Resource;
+use tokio_modbus::FunctionCode;
+
+use crate::{circuit_breaker::BreakerState, device::Devices, device_health::DeviceState};
+
+/// 所有modbus相关的指标。需要在`init_meter_provider`之后才第一次访问，否则会绑定到空的provider
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(|| Metrics::new(&global::meter("modbus")));
+
+pub struct Metrics {
+    pub requests: Counter<u64>,
+    pub request_duration: Histogram<f64>,
+    pub errors: Counter<u64>,
+    pub reconnects: Counter<u64>,
+    pub connect_duration: Histogram<f64>,
+    pub recycles: Counter<u64>,
+    pub poll_cycle_duration: Histogram<f64>,
+    pub breaker_transitions: Counter<u64>,
+}
+
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+impl Metrics {
+    pub fn new(meter: &Meter) -> Self {
+        Metrics {
+            requests: meter
+                .u64_counter("modbus_requests")
+                .with_description("发送到设备的modbus请求数")
+                .build(),
+            request_duration: meter
+                .f64_histogram("modbus_request_duration_seconds")
+                .with_description("modbus请求耗时")
+                .with_unit("s")
+                .with_boundaries(LATENCY_BUCKETS.to_vec())
+                .build(),
+            errors: meter
+                .u64_counter("modbus_errors")
+                .with_description("按类型统计的modbus请求失败数")
+                .build(),
+            reconnects: meter
+                .u64_counter("modbus_reconnects")
+                .with_description("连接池新建连接的次数")
+                .build(),
+            connect_duration: meter
+                .f64_histogram("modbus_connect_duration_seconds")
+                .with_description("建立modbus连接的耗时，按结果区分")
+                .with_unit("s")
+                .with_boundaries(LATENCY_BUCKETS.to_vec())
+                .build(),
+            recycles: meter
+                .u64_counter("modbus_recycles")
+                .with_description("连接归还时复用或丢弃的次数")
+                .build(),
+            poll_cycle_duration: meter
+                .f64_histogram("modbus_poll_cycle_duration_seconds")
+                .with_description("一次后台轮询的耗时")
+                .with_unit("s")
+                .with_boundaries(LATENCY_BUCKETS.to_vec())
+                .build(),
+            breaker_transitions: meter
+                .u64_counter("modbus_breaker_transitions")
+                .with_description("断路器状态变化次数")
+                .build(),
+        }
+    }
+
+    /// 记录一次发到设备的请求：次数和耗时，属性见`request_attrs`
+    pub fn record_request(
+        &self,
+        device: &str,
+        function: FunctionCode,
+        outcome: &'static str,
+        elapsed: Duration,
+    ) {
+        let attrs = request_attrs(device, function, outcome);
+        self.requests.add(1, &attrs);
+        self.request_duration.record(elapsed.as_secs_f64(), &attrs);
+    }
+}
+
+/// 连接池、健康状态和断路器这些都是现成的状态，用observable gauge在采集时读取
+pub fn register_device_gauges(meter: &Meter, devices: &Devices) {
+    let pool_devices = devices.clone();
+    meter
+        .u64_observable_gauge("modbus_pool_size")
+        .with_description("连接池当前连接数")
+        .with_callback(move |observer| {
+            for device in pool_devices.values() {
+                let status = device.pool.status();
+                observer.observe(status.size as u64, &device_attrs(&device.name));
+            }
+        })
+        .build();
+    let pool_devices = devices.clone();
+    meter
+        .u64_observable_gauge("modbus_pool_available")
+        .with_description("连接池空闲连接数")
+        .with_callback(move |observer| {
+            for device in pool_devices.values() {
+                let status = device.pool.status();
+                observer.observe(status.available as u64, &device_attrs(&device.name));
+            }
+        })
+        .build();
+    let pool_devices = devices.clone();
+    meter
+        .u64_observable_gauge("modbus_pool_waiting")
+        .with_description("等待连接的请求数")
+        .with_callback(move |observer| {
+            for device in pool_devices.values() {
+                let status = device.pool.status();
+                observer.observe(status.waiting as u64, &device_attrs(&device.name));
+            }
+        })
+        .build();
+    let queue_devices = devices.clone();
+    meter
+        .u64_observable_gauge("modbus_queue_depth")
+        .with_description("限速队列里等待发送的请求数")
+        .with_callback(move |observer| {
+            for device in queue_devices.values() {
+                let queue = device.limiter.snapshot();
+                for (priority, depth) in [
+                    ("control", queue.control),
+                    ("interactive", queue.interactive),
+                    ("background", queue.background),
+                ] {
+                    observer.observe(
+                        depth as u64,
+                        &[
+                            KeyValue::new("device", device.name.clone()),
+                            KeyValue::new("priority", priority),
+                        ],
+                    );
+                }
+            }
+        })
+        .build();
+    let health_devices = devices.clone();
+    meter
+        .u64_observable_gauge("modbus_device_up")
+        .with_description("设备状态，2 up，1 degraded，0 down")
+        .with_callback(move |observer| {
+            for device in health_devices.values() {
+                let value = match device.health.snapshot().state {
+                    DeviceState::Up => 2,
+                    DeviceState::Degraded => 1,
+                    DeviceState::Down => 0,
+                };
+                observer.observe(value, &device_attrs(&device.name));
+            }
+        })
+        .build();
+    let breaker_devices = devices.clone();
+    meter
+        .u64_observable_gauge("modbus_breaker_state")
+        .with_description("断路器状态，0 closed，1 open，2 half-open")
+        .with_callback(move |observer| {
+            for device in breaker_devices.values() {
+                let value = match device.breaker.snapshot().state {
+                    BreakerState::Closed => 0,
+                    BreakerState::Open => 1,
+                    BreakerState::HalfOpen => 2,
+                };
+                observer.observe(value, &device_attrs(&device.name));
+            }
+        })
+        .build();
+}
+
+pub fn device_attrs(device: &str) -> [KeyValue; 1] {
+    [KeyValue::new("device", device.to_string())]
+}
+
+/// 给/metrics用的拉取式reader，`with_reader`会拿走所有权，所以用Arc共享一份
+#[derive(Debug, Clone)]
+pub struct PrometheusReader(Arc<ManualReader>);
+
+impl PrometheusReader {
+    pub fn new() -> Self {
+        PrometheusReader(Arc::new(ManualReader::builder().build()))
+    }
+
+    /// 采集一次并输出Prometheus文本格式
+    pub fn render(&self) -> MetricResult<String> {
+        let mut rm = ResourceMetrics {
+            resource: Resource::empty(),
+            scope_metrics: Vec::new(),
+        };
+        self.0.collect(&mut rm)?;
+        let mut out = String::new();
+        for scope in &rm.scope_metrics {
+            for metric in &scope.metrics {
+                write_metric(&mut out, metric);
+            }
+        }
+        Ok(out)
+    }
+}
+
+impl MetricReader for PrometheusReader {
+    fn register_pipeline(&self, pipeline: Weak<Pipeline>) {
+        self.0.register_pipeline(pipeline)
+    }
+    fn collect(&self, rm: &mut ResourceMetrics) -> MetricResult<()> {
+        self.0.collect(rm)
+    }
+    fn force_flush(&self) -> MetricResult<()> {
+        self.0.force_flush()
+    }
+    fn shutdown(&self) -> MetricResult<()> {
+        self.0.shutdown()
+    }
+    fn temporality(&self, kind: InstrumentKind) -> Temporality {
+        self.0.temporality(kind)
+    }
+}
+
+/// meter provider的公共部分，测试时可以在这上面再加一个in-memory exporter的reader
+pub fn meter_provider_builder(
+    prometheus: &PrometheusReader,
+    resource: Resource,
+) -> MeterProviderBuilder {
+    SdkMeterProvider::builder()
+        .with_reader(prometheus.clone())
+        .with_resource(resource)
+}
+
+/// 创建meter provider并注册为全局，OTLP的reader可选
+pub fn init_meter_provider(
+    prometheus: &PrometheusReader,
+    otlp: Option<PeriodicReader>,
+    resource: Resource,
+) -> SdkMeterProvider {
+    let mut builder = meter_provider_builder(prometheus, resource);
+    if let Some(otlp) = otlp {
+        builder = builder.with_reader(otlp);
+    }
+    let provider = builder.build();
+    global::set_meter_provider(provider.clone());
+    provider
+}
+
+/// 请求指标的属性：设备名、功能码和结果
+fn request_attrs(device: &str, function: FunctionCode, outcome: &'static str) -> [KeyValue; 4] {
+    [
+        KeyValue::new("device", device.to_string()),
+        KeyValue::new("function_code", function.value() as i64),
+        KeyValue::new("operation", format!("{:?}", function)),
+        KeyValue::new("outcome", outcome),
+    ]
+}
+
+fn write_metric(out: &mut String, metric: &data::Metric) {
+    let name = sanitize(&metric.name);
+    let data = metric.data.as_any();
+    if let Some(sum) = data.downcast_ref::<data::Sum<u64>>() {
+        write_sum(
+            out,
+            &name,
+            &metric.description,
+            sum.is_monotonic,
+            &sum.data_points,
+        );
+    } else if let Some(sum) = data.downcast_ref::<data::Sum<i64>>() {
+        write_sum(
+            out,
+            &name,
+            &metric.description,
+            sum.is_monotonic,
+            &sum.data_points,
+        );
+    } else if let Some(sum) = data.downcast_ref::<data::Sum<f64>>() {
+        write_sum(
+            out,
+            &name,
+            &metric.description,
+            sum.is_monotonic,
+            &sum.data_points,
+        );
+    } else if let Some(gauge) = data.downcast_ref::<data::Gauge<u64>>() {
+        write_gauge(out, &name, &metric.description, &gauge.data_points);
+    } else if let Some(gauge) = data.downcast_ref::<data::Gauge<i64>>() {
+        write_gauge(out, &name, &metric.description, &gauge.data_points);
+    } else if let Some(gauge) = data.downcast_ref::<data::Gauge<f64>>() {
+        write_gauge(out, &name, &metric.description, &gauge.data_points);
+    } else if let Some(histogram) = data.downcast_ref::<data::Histogram<f64>>() {
+        write_histogram(out, &name, &metric.description, &histogram.data_points);
+    } else if let Some(histogram) = data.downcast_ref::<data::Histogram<u64>>() {
+        write_histogram(out, &name, &metric.description, &histogram.data_points);
+    }
+}
+
+fn write_sum<T: ToString>(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    monotonic: bool,
+    points: &[data::DataPoint<T>],
+) {
+    let (name, kind) = if monotonic {
+        (format!("{}_total", name), "counter")
+    } else {
+        (name.to_string(), "gauge")
+    };
+    write_header(out, &name, help, kind);
+    for point in points {
+        let _ = writeln!(
+            out,
+            "{}{} {}",
+            name,
+            labels(&point.attributes, None),
+            point.value.to_string()
+        );
+    }
+}
+
+fn write_gauge<T: ToString>(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    points: &[data::DataPoint<T>],
+) {
+    write_header(out, name, help, "gauge");
+    for point in points {
+        let _ = writeln!(
+            out,
+            "{}{} {}",
+            name,
+            labels(&point.attributes, None),
+            point.value.to_string()
+        );
+    }
+}
+
+fn write_histogram<T: ToString>(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    points: &[data::HistogramDataPoint<T>],
+) {
+    write_header(out, name, help, "histogram");
+    for point in points {
+        let mut cumulative = 0;
+        for (i, count) in point.bucket_counts.iter().enumerate() {
+            cumulative += count;
+            let le = point
+                .bounds
+                .get(i)
+                .map(|bound| bound.to_string())
+                .unwrap_or_else(|| "+Inf".to_string());
+            let _ = writeln!(
+                out,
+                "{}_bucket{} {}",
+                name,
+                labels(&point.attributes, Some(&le)),
+                cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_sum{} {}",
+            name,
+            labels(&point.attributes, None),
+            point.sum.to_string()
+        );
+        let _ = writeln!(
+            out,
+            "{}_count{} {}",
+            name,
+            labels(&point.attributes, None),
+            point.count
+        );
+    }
+}
+
+fn write_header(out: &mut String, name: &str, help: &str, kind: &str) {
+    if !help.is_empty() {
+        let _ = writeln!(out, "# HELP {} {}", name, help.replace('\n', " "));
+    }
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn labels(attributes: &[KeyValue], le: Option<&str>) -> String {
+    let mut pairs: Vec<String> = attributes
+        .iter()
+        .map(|kv| format!("{}=\"{}\"", sanitize(kv.key.as_str()), escape(&kv.value)))
+        .collect();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{}\"", le));
+    }
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn escape(value: &Value) -> String {
+    value
+        .as_str()
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::metrics::MeterProvider;
+    use opentelemetry_sdk::{
+        metrics::PeriodicReader, runtime, testing::metrics::InMemoryMetricExporter,
+    };
+
+    use super::*;
+
+    /// 找到指定名字的计数器，返回每个数据点的属性和值
+    fn counter_points(exporter: &InMemoryMetricExporter, name: &str) -> Vec<(Vec<KeyValue>, u64)> {
+        let metrics = exporter.get_finished_metrics().unwrap();
+        let metric = metrics
+            .iter()
+            .flat_map(|rm| &rm.scope_metrics)
+            .flat_map(|scope| &scope.metrics)
+            .find(|metric| metric.name == name)
+            .unwrap_or_else(|| panic!("没有指标{}", name));
+        let sum = metric
+            .data
+            .as_any()
+            .downcast_ref::<data::Sum<u64>>()
+            .unwrap();
+        sum.data_points
+            .iter()
+            .map(|point| (point.attributes.clone(), point.value))
+            .collect()
+    }
+
+    fn attr<'a>(attributes: &'a [KeyValue], key: &str) -> Option<&'a Value> {
+        attributes
+            .iter()
+            .find(|kv| kv.key.as_str() == key)
+            .map(|kv| &kv.value)
+    }
+
+    #[actix_web::test]
+    async fn request_metrics_have_device_function_and_outcome() {
+        let exporter = InMemoryMetricExporter::default();
+        let provider = meter_provider_builder(&PrometheusReader::new(), Resource::empty())
+            .with_reader(
+                PeriodicReader::builder(exporter.clone(), runtime::TokioCurrentThread).build(),
+            )
+            .build();
+        let metrics = Metrics::new(&provider.meter("modbus"));
+
+        let elapsed = Duration::from_millis(10);
+        metrics.record_request(
+            "main",
+            FunctionCode::ReadHoldingRegisters,
+            "success",
+            elapsed,
+        );
+        metrics.record_request(
+            "main",
+            FunctionCode::ReadHoldingRegisters,
+            "success",
+            elapsed,
+        );
+        metrics.record_request(
+            "finished",
+            FunctionCode::WriteSingleRegister,
+            "timeout",
+            elapsed,
+        );
+        provider.force_flush().unwrap();
+
+        let mut points = counter_points(&exporter, "modbus_requests");
+        points.sort_by_key(|(_, value)| *value);
+        assert_eq!(points.len(), 2);
+
+        let (timeout, count) = &points[0];
+        assert_eq!(*count, 1);
+        assert_eq!(attr(timeout, "device"), Some(&Value::from("finished")));
+        assert_eq!(attr(timeout, "function_code"), Some(&Value::I64(6)));
+        assert_eq!(attr(timeout, "outcome"), Some(&Value::from("timeout")));
+
+        let (success, count) = &points[1];
+        assert_eq!(*count, 2);
+        assert_eq!(attr(success, "device"), Some(&Value::from("main")));
+        assert_eq!(attr(success, "function_code"), Some(&Value::I64(3)));
+        assert_eq!(attr(success, "outcome"), Some(&Value::from("success")));
+    }
+}
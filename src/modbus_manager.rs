@@ -1,22 +1,124 @@
 use actix_web::rt::time::timeout;
 use client::Context;
 use deadpool::managed::{self, RecycleError};
-use std::{net::SocketAddr, time::Duration};
+use opentelemetry::KeyValue;
+use std::{
+    fmt, io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context as TaskContext, Poll},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::TcpStream,
+};
 use tokio_modbus::prelude::*;
-use tracing::{debug, error, info};
+use tracing::{debug, error, field, info_span, Instrument};
+
+use crate::device::DeviceError;
+use crate::metrics::{device_attrs, METRICS};
+use crate::modbus_tls::ModbusTls;
 pub type Pool = managed::Pool<ModbusManager>;
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 pub struct ModbusManager {
+    pub name: String,
     pub addr: String,
     pub slave: u8,
+    /// 配置了Modbus/TCP Security时用TLS连接
+    pub tls: Option<Arc<ModbusTls>>,
 }
 #[derive(Debug)]
 pub struct Modbus {
     pub addr: String,
     pub slave: u8,
     pub context: Context,
+    /// 和context是同一个连接，透明代理用它收发原始报文
+    stream: SharedStream,
+    /// 原始报文用的事务号
+    transaction_id: u16,
     pub status: bool,
 }
+
+trait Transport: AsyncRead + AsyncWrite + Send {}
+impl<T: AsyncRead + AsyncWrite + Send> Transport for T {}
+
+/// 到设备的TCP或TLS连接，context和原始报文共用。
+/// 连接从池里取出后只有一个持有者，两种用法不会交错，tokio-modbus每次请求前也会清空自己的读缓冲
+#[derive(Clone)]
+struct SharedStream(Arc<Mutex<Pin<Box<dyn Transport>>>>);
+
+impl SharedStream {
+    fn new(stream: impl Transport + 'static) -> Self {
+        SharedStream(Arc::new(Mutex::new(Box::pin(stream))))
+    }
+}
+impl fmt::Debug for SharedStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SharedStream")
+    }
+}
+impl AsyncRead for SharedStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.0.lock().unwrap().as_mut().poll_read(cx, buf)
+    }
+}
+impl AsyncWrite for SharedStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.0.lock().unwrap().as_mut().poll_write(cx, buf)
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        self.0.lock().unwrap().as_mut().poll_flush(cx)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        self.0.lock().unwrap().as_mut().poll_shutdown(cx)
+    }
+}
+
+impl Modbus {
+    /// 发送原始请求PDU，返回设备响应的原始PDU，异常响应也原样返回。
+    /// 响应的事务号、unit id或功能码对不上时返回错误，调用方应当丢弃这个连接
+    pub async fn call_raw(&mut self, unit_id: u8, pdu: &[u8]) -> io::Result<Vec<u8>> {
+        self.transaction_id = self.transaction_id.wrapping_add(1);
+        let mut frame = Vec::with_capacity(7 + pdu.len());
+        frame.extend_from_slice(&self.transaction_id.to_be_bytes());
+        frame.extend_from_slice(&[0, 0]);
+        frame.extend_from_slice(&(pdu.len() as u16 + 1).to_be_bytes());
+        frame.push(unit_id);
+        frame.extend_from_slice(pdu);
+        self.stream.write_all(&frame).await?;
+        self.stream.flush().await?;
+
+        let mut header = [0u8; 7];
+        self.stream.read_exact(&mut header).await?;
+        let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+        if header[2..4] != [0, 0] || length < 2 {
+            return Err(invalid_data("无效的MBAP头"));
+        }
+        let mut response = vec![0u8; length - 1];
+        self.stream.read_exact(&mut response).await?;
+        if header[0..2] != self.transaction_id.to_be_bytes() || header[6] != unit_id {
+            return Err(invalid_data("响应的事务号或unit id和请求不一致"));
+        }
+        if response[0] & 0x7F != pdu[0] {
+            return Err(invalid_data("响应的功能码和请求不一致"));
+        }
+        Ok(response)
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
 #[derive(Debug)]
 pub enum Error {
     Fail,
@@ -26,27 +128,63 @@ impl managed::Manager for ModbusManager {
     type Error = Error;
 
     async fn create(&self) -> Result<Modbus, Error> {
+        METRICS.reconnects.add(1, &device_attrs(&self.name));
+        let span = info_span!(
+            "modbus.connect",
+            modbus.device = %self.name,
+            server.address = %self.addr,
+            modbus.unit_id = self.slave,
+            modbus.latency_ms = field::Empty,
+            otel.status_code = field::Empty,
+        );
+        let started = Instant::now();
         let socket_addr = self.addr.parse::<SocketAddr>().unwrap();
-        match timeout(
-            Duration::from_millis(1000),
-            tcp::connect_slave(socket_addr, Slave(self.slave)),
-        )
-        .await
+        let connect = async {
+            io::Result::Ok(match &self.tls {
+                Some(tls) => SharedStream::new(tls.connect(socket_addr).await?),
+                None => SharedStream::new(TcpStream::connect(socket_addr).await?),
+            })
+        };
+        let result = match timeout(Duration::from_millis(1000), connect)
+            .instrument(span.clone())
+            .await
         {
-            Ok(Ok(context)) => {
+            Ok(Ok(stream)) => {
                 debug!("连接modbus:{},成功", self.addr);
                 Ok(Modbus {
                     addr: self.addr.clone(),
                     slave: self.slave,
-                    context: context,
+                    context: tcp::attach_slave(stream.clone(), Slave(self.slave)),
+                    stream,
+                    transaction_id: 0,
                     status: true,
                 })
             }
-            _ => {
-                error!("连接modbus:{}，失败", self.addr);
+            Ok(Err(err)) => {
+                error!("连接modbus:{}，失败：{}", self.addr, err);
+                Err(Error::Fail)
+            }
+            Err(_) => {
+                error!("连接modbus:{}，超时", self.addr);
                 Err(Error::Fail)
             }
+        };
+        let outcome = if result.is_ok() { "success" } else { "failure" };
+        span.record(
+            "modbus.latency_ms",
+            started.elapsed().as_secs_f64() * 1000.0,
+        );
+        if result.is_err() {
+            span.record("otel.status_code", "ERROR");
         }
+        METRICS.connect_duration.record(
+            started.elapsed().as_secs_f64(),
+            &[
+                KeyValue::new("device", self.name.clone()),
+                KeyValue::new("outcome", outcome),
+            ],
+        );
+        result
     }
 
     async fn recycle(
@@ -56,32 +194,58 @@ impl managed::Manager for ModbusManager {
     ) -> managed::RecycleResult<Error> {
         //如果每次都需要连接一下在使用，则整体的效率会变慢一倍
         //所以应该在每次从池中取出来的modbus实例应该判断状态，在用重试机制调用
-        match conn.status {
+        let result = match conn.status {
             true => Ok(()),
             _ => {
-                conn.context.disconnect().await.unwrap();
-                debug!("断开连接成功！");
+                match conn.context.disconnect().await {
+                    Ok(()) => debug!("断开modbus:{}(slave {})连接成功！", conn.addr, conn.slave),
+                    Err(err) => debug!("断开modbus:{}连接失败：{}", conn.addr, err),
+                }
                 Err(RecycleError::Message(std::borrow::Cow::Borrowed(
                     "can't recycle",
                 )))
             }
-        }
+        };
+        let outcome = if result.is_ok() {
+            "reused"
+        } else {
+            "discarded"
+        };
+        METRICS.recycles.add(
+            1,
+            &[
+                KeyValue::new("device", self.name.clone()),
+                KeyValue::new("outcome", outcome),
+            ],
+        );
+        result
     }
     fn detach(&self, _obj: &mut Self::Type) {}
 }
 impl ModbusManager {}
 
-async fn is_connection_alive(context: &mut Context) -> bool {
+/// 读一个保持寄存器来确认连接是否可用
+pub async fn check_connection(
+    context: &mut Context,
+    register: u16,
+    timeout_ms: u64,
+) -> Result<(), DeviceError> {
     match timeout(
-        Duration::from_millis(100),
-        context.read_holding_registers(0x00, 1),
+        Duration::from_millis(timeout_ms),
+        context.read_holding_registers(register, 1),
     )
     .await
     {
-        Ok(Ok(_)) => true,
-        _ => {
+        Ok(Ok(Ok(_))) => Ok(()),
+        // 设备返回了异常码，说明链路是通的，但探测寄存器配置可能有问题
+        Ok(Ok(Err(code))) => Err(DeviceError::Exception(code)),
+        Ok(Err(err)) => {
+            error!("connect error!");
+            Err(DeviceError::Io(err))
+        }
+        Err(_) => {
             error!("connect error!");
-            false
+            Err(DeviceError::Timeout)
         }
     }
 }
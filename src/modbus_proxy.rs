@@ -0,0 +1,557 @@
+use std::{io, net::SocketAddr, sync::Arc};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tokio_modbus::{bytes::Bytes, ExceptionCode, Request};
+use tracing::{error, info, warn};
+
+use crate::{
+    app_config::ProxyConfig,
+    audit::{AuditLog, AuditRecord},
+    commands::{audit_record, Origin},
+    device::{Device, DeviceError, Turn},
+    modbus_server::{device_exception, policy_exception},
+    rate_limiter::Priority,
+    write_policy::{self, PolicyViolation},
+};
+
+/// 设备的透明代理：客户端的读请求和允许的写请求原样经过设备的连接池和限速队列发给设备，
+/// 设备响应的PDU原样返回，unit id保持客户端的，事务号由代理到设备的连接重新分配
+pub struct ModbusProxy {
+    device: Arc<Device>,
+    config: ProxyConfig,
+    audit: Arc<AuditLog>,
+}
+
+/// 要经过写入策略和审计的写请求
+enum Write {
+    /// 功能码5、15，检查只读和允许地址
+    Coils { address: u16, values: Vec<u16> },
+    /// 功能码6、16、23，和HTTP写入一样检查写入策略和联锁
+    Registers { address: u16, values: Vec<u16> },
+    /// 功能码22，检查联锁
+    Mask { address: u16 },
+}
+
+impl Write {
+    /// 写功能码返回Some，请求格式不对时为Some(Err)
+    fn parse(pdu: &[u8]) -> Option<Result<Write, ExceptionCode>> {
+        if !matches!(pdu[0], 5 | 6 | 15 | 16 | 22 | 23) {
+            return None;
+        }
+        let Ok(request) = Request::try_from(Bytes::copy_from_slice(pdu)) else {
+            return Some(Err(ExceptionCode::IllegalDataValue));
+        };
+        Some(Ok(match request {
+            Request::WriteSingleCoil(address, value) => Write::Coils {
+                address,
+                values: vec![value as u16],
+            },
+            Request::WriteMultipleCoils(address, coils) => Write::Coils {
+                address,
+                values: coils.iter().map(|&coil| coil as u16).collect(),
+            },
+            Request::WriteSingleRegister(address, value) => Write::Registers {
+                address,
+                values: vec![value],
+            },
+            Request::WriteMultipleRegisters(address, values)
+            | Request::ReadWriteMultipleRegisters(_, _, address, values) => Write::Registers {
+                address,
+                values: values.into_owned(),
+            },
+            Request::MaskWriteRegister(address, _, _) => Write::Mask { address },
+            _ => unreachable!("write function codes decode to write requests"),
+        }))
+    }
+
+    fn address(&self) -> u16 {
+        match self {
+            Write::Coils { address, .. }
+            | Write::Registers { address, .. }
+            | Write::Mask { address } => *address,
+        }
+    }
+
+    /// 审计日志里记录的新值，掩码写没有
+    fn values(&self) -> Vec<u16> {
+        match self {
+            Write::Coils { values, .. } | Write::Registers { values, .. } => values.clone(),
+            Write::Mask { .. } => Vec::new(),
+        }
+    }
+
+    /// 要检查联锁的寄存器数量，线圈不检查
+    fn interlocked(&self) -> Option<usize> {
+        match self {
+            Write::Coils { .. } => None,
+            Write::Registers { values, .. } => Some(values.len()),
+            Write::Mask { .. } => Some(1),
+        }
+    }
+}
+
+/// 转发的读功能码：1、2、3、4和读设备标识（0x2B/0x0E）
+fn is_read(pdu: &[u8]) -> bool {
+    match pdu[0] {
+        1..=4 => true,
+        0x2B => pdu.get(1) == Some(&0x0E),
+        _ => false,
+    }
+}
+
+impl ModbusProxy {
+    pub fn new(device: Arc<Device>, config: ProxyConfig, audit: Arc<AuditLog>) -> Self {
+        ModbusProxy {
+            device,
+            config,
+            audit,
+        }
+    }
+
+    fn check(&self, write: &Write) -> Result<(), PolicyViolation> {
+        match write {
+            Write::Coils { address, values } => {
+                write_policy::check_coils(&self.device, *address, values.len())
+            }
+            Write::Registers { address, values } => {
+                write_policy::check(&self.device, *address, values)
+            }
+            Write::Mask { address } => write_policy::check_mask_write(&self.device, *address),
+        }
+    }
+
+    /// 转发一个请求PDU，返回给客户端的响应PDU
+    async fn forward(&self, peer: SocketAddr, unit_id: u8, pdu: &[u8]) -> Vec<u8> {
+        let function = pdu[0];
+        let write = match Write::parse(pdu) {
+            None if is_read(pdu) => {
+                return match self.send(peer, unit_id, pdu, None).await {
+                    Ok(response) => response,
+                    Err(err) => exception(function, device_exception(err)),
+                };
+            }
+            // 诊断、文件记录、厂商自定义等功能码可能改设备的状态，不转发
+            None => {
+                warn!(
+                    "设备{}的代理拒绝了{}的功能码{}",
+                    self.device.name, peer, function
+                );
+                return exception(function, ExceptionCode::IllegalFunction);
+            }
+            Some(Ok(write)) => write,
+            Some(Err(code)) => return exception(function, code),
+        };
+        if !self.config.writable {
+            return exception(function, ExceptionCode::IllegalFunction);
+        }
+        if let Err(violation) = self.check(&write) {
+            return self.reject(peer, unit_id, function, &write, violation);
+        }
+        // 联锁检查和写入在同一个限速许可里，中间联锁寄存器不会被别的请求改掉
+        let turn = match self.device.turn(Priority::Control).await {
+            Ok(turn) => turn,
+            Err(err) => {
+                self.record(peer, unit_id, function, &write, err.to_string());
+                return exception(function, device_exception(err));
+            }
+        };
+        if let Some(count) = write.interlocked() {
+            if let Err(violation) =
+                write_policy::check_interlocks(&turn, write.address(), count).await
+            {
+                return self.reject(peer, unit_id, function, &write, violation);
+            }
+        }
+        let result = self.send(peer, unit_id, pdu, Some(&turn)).await;
+        drop(turn);
+        let outcome = match &result {
+            Ok(response) if response[0] & 0x80 != 0 => {
+                let code = response.get(1).copied().unwrap_or_default();
+                DeviceError::Exception(ExceptionCode::new(code)).to_string()
+            }
+            Ok(_) => "success".to_string(),
+            Err(err) => err.to_string(),
+        };
+        self.record(peer, unit_id, function, &write, outcome);
+        match result {
+            Ok(response) => response,
+            Err(err) => exception(function, device_exception(err)),
+        }
+    }
+
+    /// 发给设备，写请求在已经拿到的限速许可里发，读请求自己排队
+    async fn send(
+        &self,
+        peer: SocketAddr,
+        unit_id: u8,
+        pdu: &[u8],
+        turn: Option<&Turn<'_>>,
+    ) -> Result<Vec<u8>, DeviceError> {
+        if self.config.log_frames {
+            info!(
+                "设备{}代理转发{}的请求，unit {}：{}",
+                self.device.name,
+                peer,
+                unit_id,
+                hex(pdu)
+            );
+        }
+        let result = match turn {
+            Some(turn) => turn.call_raw(unit_id, pdu).await,
+            None => {
+                self.device
+                    .call_raw(Priority::Interactive, unit_id, pdu)
+                    .await
+            }
+        };
+        if self.config.log_frames {
+            match &result {
+                Ok(response) => info!(
+                    "设备{}代理收到响应，unit {}：{}",
+                    self.device.name,
+                    unit_id,
+                    hex(response)
+                ),
+                Err(err) => info!("设备{}代理转发失败：{}", self.device.name, err),
+            }
+        }
+        result
+    }
+
+    fn reject(
+        &self,
+        peer: SocketAddr,
+        unit_id: u8,
+        function: u8,
+        write: &Write,
+        violation: PolicyViolation,
+    ) -> Vec<u8> {
+        warn!(
+            "设备{}的代理拒绝了{}的写入，功能码{}地址{}：{}",
+            self.device.name,
+            peer,
+            function,
+            write.address(),
+            violation
+        );
+        self.record(
+            peer,
+            unit_id,
+            function,
+            write,
+            format!("rejected: {}", violation),
+        );
+        exception(function, policy_exception(&violation))
+    }
+
+    fn record(&self, peer: SocketAddr, unit_id: u8, function: u8, write: &Write, outcome: String) {
+        let origin = Origin {
+            client_ip: Some(peer.ip().to_string()),
+            identity: Some("modbus-proxy".to_string()),
+            command: Some(format!("功能码{}", function)),
+        };
+        self.audit.record(AuditRecord {
+            unit_id,
+            ..audit_record(
+                &self.device,
+                write.address(),
+                None,
+                write.values(),
+                outcome,
+                &origin,
+            )
+        });
+    }
+
+    /// 按顺序处理一个客户端连接上的请求，直到客户端断开
+    async fn connection(&self, mut stream: TcpStream, peer: SocketAddr) -> io::Result<()> {
+        let mut header = [0u8; 7];
+        loop {
+            match stream.read_exact(&mut header).await {
+                Ok(_) => {}
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(err) => return Err(err),
+            }
+            // 长度包括unit id，协议规定PDU最长253字节
+            let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+            if header[2..4] != [0, 0] || !(2..=254).contains(&length) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "无效的MBAP头"));
+            }
+            let mut pdu = vec![0u8; length - 1];
+            stream.read_exact(&mut pdu).await?;
+            let response = self.forward(peer, header[6], &pdu).await;
+            let mut frame = Vec::with_capacity(7 + response.len());
+            frame.extend_from_slice(&header[..4]);
+            frame.extend_from_slice(&(response.len() as u16 + 1).to_be_bytes());
+            frame.push(header[6]);
+            frame.extend_from_slice(&response);
+            stream.write_all(&frame).await?;
+        }
+    }
+}
+
+fn exception(function: u8, code: ExceptionCode) -> Vec<u8> {
+    vec![function | 0x80, code.into()]
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// 接受调试工具的连接，直到进程退出
+pub async fn serve(proxy: Arc<ModbusProxy>, listener: TcpListener) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                error!("设备{}的代理停止监听：{}", proxy.device.name, err);
+                return;
+            }
+        };
+        info!("设备{}的代理接受连接{}", proxy.device.name, peer);
+        let proxy = proxy.clone();
+        actix_web::rt::spawn(async move {
+            if let Err(err) = proxy.connection(stream, peer).await {
+                warn!("设备{}的代理连接{}出错：{}", proxy.device.name, peer, err);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use actix_web::rt::{spawn, time::timeout};
+    use futures_util::future::join_all;
+
+    use super::*;
+    use crate::{
+        device::build_devices,
+        test_support::{audit_log, modbus_config, start_simulator, Simulator},
+    };
+
+    /// 启动连到模拟设备的代理，返回代理的地址
+    async fn start_proxy(simulator: Simulator, writable: bool, audit: Arc<AuditLog>) -> SocketAddr {
+        // 模拟设备的寄存器值等于地址，写11要求寄存器5为0，永远不满足
+        let config = modbus_config(&format!(
+            r#"
+            address = "{}"
+            slave_id = 1
+            name = "main"
+            write_policy = {{ allowed = [[10, 19]], interlocks = [{{ register = 11, require_register = 5, require_value = 0 }}, {{ register = 12, require_register = 0, require_value = 0 }}] }}
+            proxy = {{ listen = "127.0.0.1:0", writable = {} }}
+            "#,
+            start_simulator(simulator).await,
+            writable
+        ));
+        let proxy_config = config.proxy.clone().unwrap();
+        let devices = build_devices(&[config]).unwrap();
+        let proxy = ModbusProxy::new(devices["main"].clone(), proxy_config, audit);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        spawn(serve(Arc::new(proxy), listener));
+        address
+    }
+
+    /// 发一个请求帧，返回响应帧
+    async fn exchange(stream: &mut TcpStream, frame: &[u8]) -> Vec<u8> {
+        stream.write_all(frame).await.unwrap();
+        let mut header = [0u8; 7];
+        timeout(Duration::from_secs(5), stream.read_exact(&mut header))
+            .await
+            .unwrap()
+            .unwrap();
+        let mut response = header.to_vec();
+        response.resize(6 + u16::from_be_bytes([header[4], header[5]]) as usize, 0);
+        stream.read_exact(&mut response[7..]).await.unwrap();
+        response
+    }
+
+    #[actix_web::test]
+    async fn forwards_raw_pdus_with_the_client_ids() {
+        let simulator = Simulator::default();
+        let proxy = start_proxy(simulator.clone(), false, audit_log("proxy-forward")).await;
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+
+        // 读保持寄存器：事务号和unit id是客户端的
+        let response = exchange(&mut stream, &[0x12, 0x34, 0, 0, 0, 6, 7, 3, 0, 10, 0, 2]).await;
+        assert_eq!(response, [0x12, 0x34, 0, 0, 0, 7, 7, 3, 4, 0, 10, 0, 11]);
+
+        // 读设备标识原样转发
+        let response = exchange(&mut stream, &[0, 1, 0, 0, 0, 5, 7, 0x2B, 0x0E, 1, 0]).await;
+        assert_eq!(response, [0, 1, 0, 0, 0, 5, 7, 0x2B, 0x0E, 1, 0]);
+
+        // 设备的异常响应原样返回
+        let response = exchange(&mut stream, &[0, 2, 0, 0, 0, 6, 7, 4, 0, 0, 0, 1]).await;
+        assert_eq!(response, [0, 2, 0, 0, 0, 3, 7, 0x84, 1]);
+
+        // 不允许写时写请求不转发
+        let response = exchange(&mut stream, &[0, 3, 0, 0, 0, 6, 7, 5, 0, 1, 0xFF, 0]).await;
+        assert_eq!(response, [0, 3, 0, 0, 0, 3, 7, 0x85, 1]);
+        let response = exchange(&mut stream, &[0, 4, 0, 0, 0, 6, 7, 6, 0, 12, 0, 1]).await;
+        assert_eq!(response, [0, 4, 0, 0, 0, 3, 7, 0x86, 1]);
+
+        // 诊断、MEI的其他类型、异常标志位都不转发
+        let response = exchange(&mut stream, &[0, 5, 0, 0, 0, 6, 7, 8, 0, 0, 0xAB, 0xCD]).await;
+        assert_eq!(response, [0, 5, 0, 0, 0, 3, 7, 0x88, 1]);
+        let response = exchange(&mut stream, &[0, 6, 0, 0, 0, 5, 7, 0x2B, 0x0D, 0, 0]).await;
+        assert_eq!(response, [0, 6, 0, 0, 0, 3, 7, 0xAB, 1]);
+        let response = exchange(&mut stream, &[0, 7, 0, 0, 0, 6, 7, 0x83, 0, 0, 0, 1]).await;
+        assert_eq!(response, [0, 7, 0, 0, 0, 3, 7, 0x83, 1]);
+
+        assert_eq!(*simulator.unit_ids.lock().unwrap(), [7, 7, 7]);
+        assert!(simulator.writes.lock().unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn unknown_function_codes_are_not_forwarded_when_writable() {
+        let simulator = Simulator::default();
+        let audit = audit_log("proxy-unknown");
+        let proxy = start_proxy(simulator.clone(), true, audit.clone()).await;
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+
+        // 写文件记录
+        let response = exchange(
+            &mut stream,
+            &[0, 1, 0, 0, 0, 12, 7, 21, 9, 6, 0, 1, 0, 0, 0, 1, 0, 5],
+        )
+        .await;
+        assert_eq!(response, [0, 1, 0, 0, 0, 3, 7, 0x95, 1]);
+        // 厂商自定义功能码
+        let response = exchange(&mut stream, &[0, 2, 0, 0, 0, 3, 7, 0x41, 1]).await;
+        assert_eq!(response, [0, 2, 0, 0, 0, 3, 7, 0xC1, 1]);
+
+        assert!(simulator.unit_ids.lock().unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn writes_are_checked_and_audited() {
+        let simulator = Simulator::default();
+        let audit = audit_log("proxy-writes");
+        let proxy = start_proxy(simulator.clone(), true, audit.clone()).await;
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+
+        // 联锁寄存器0为0，允许写12
+        let response = exchange(&mut stream, &[0, 1, 0, 0, 0, 6, 3, 6, 0, 12, 0, 99]).await;
+        assert_eq!(response, [0, 1, 0, 0, 0, 6, 3, 6, 0, 12, 0, 99]);
+
+        // 地址20不在允许范围内
+        let response = exchange(&mut stream, &[0, 2, 0, 0, 0, 6, 3, 6, 0, 20, 0, 1]).await;
+        assert_eq!(response, [0, 2, 0, 0, 0, 3, 3, 0x86, 2]);
+
+        // 联锁寄存器5不为0，不允许写11
+        let response = exchange(
+            &mut stream,
+            &[0, 3, 0, 0, 0, 11, 3, 16, 0, 10, 0, 2, 4, 0, 1, 0, 2],
+        )
+        .await;
+        assert_eq!(response, [0, 3, 0, 0, 0, 3, 3, 0x90, 4]);
+
+        // 线圈和寄存器共用允许地址
+        let response = exchange(
+            &mut stream,
+            &[0, 4, 0, 0, 0, 8, 3, 15, 0, 15, 0, 3, 1, 0b101],
+        )
+        .await;
+        assert_eq!(response, [0, 4, 0, 0, 0, 6, 3, 15, 0, 15, 0, 3]);
+        let response = exchange(
+            &mut stream,
+            &[0, 5, 0, 0, 0, 8, 3, 15, 0, 100, 0, 3, 1, 0b101],
+        )
+        .await;
+        assert_eq!(response, [0, 5, 0, 0, 0, 3, 3, 0x8F, 2]);
+
+        assert_eq!(
+            *simulator.writes.lock().unwrap(),
+            [
+                Request::WriteSingleRegister(12, 99),
+                Request::WriteMultipleCoils(15, vec![true, false, true].into()),
+            ]
+        );
+        // 联锁检查用设备配置的slave id读
+        assert_eq!(*simulator.unit_ids.lock().unwrap(), [1, 3, 1, 3]);
+        // 最新的在前
+        let records: Vec<_> = audit
+            .recent(10)
+            .iter()
+            .rev()
+            .map(|record| {
+                (
+                    record.unit_id,
+                    record.address,
+                    record.new_values.clone(),
+                    record.outcome.clone(),
+                    record.command.clone().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            records,
+            [
+                (
+                    3,
+                    12,
+                    vec![99],
+                    "success".to_string(),
+                    "功能码6".to_string()
+                ),
+                (
+                    3,
+                    20,
+                    vec![1],
+                    "rejected: 地址20不允许写入".to_string(),
+                    "功能码6".to_string()
+                ),
+                (
+                    3,
+                    10,
+                    vec![1, 2],
+                    "rejected: 联锁：寄存器5为5，不等于0，不允许写地址11".to_string(),
+                    "功能码16".to_string()
+                ),
+                (
+                    3,
+                    15,
+                    vec![1, 0, 1],
+                    "success".to_string(),
+                    "功能码15".to_string()
+                ),
+                (
+                    3,
+                    100,
+                    vec![1, 0, 1],
+                    "rejected: 地址100不允许写入".to_string(),
+                    "功能码15".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[actix_web::test]
+    async fn concurrent_clients_with_colliding_transaction_ids() {
+        let proxy = start_proxy(Simulator::default(), false, audit_log("proxy-concurrent")).await;
+        let clients = (0..2u8).map(|client| async move {
+            let mut stream = TcpStream::connect(proxy).await.unwrap();
+            for round in 0..20u8 {
+                // 两个客户端用同样的事务号，读不同的地址
+                let address = client as u16 * 100 + round as u16;
+                let [high, low] = address.to_be_bytes();
+                let response = exchange(
+                    &mut stream,
+                    &[0, round, 0, 0, 0, 6, client, 3, high, low, 0, 1],
+                )
+                .await;
+                assert_eq!(response, [0, round, 0, 0, 0, 5, client, 3, 2, high, low]);
+            }
+        });
+        join_all(clients).await;
+    }
+}
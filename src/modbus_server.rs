@@ -0,0 +1,476 @@
+use std::{future::Future, io, net::SocketAddr, pin::Pin, sync::Arc};
+
+use tokio::net::TcpListener;
+use tokio_modbus::{
+    server::{tcp::Server, Service},
+    ExceptionCode, Request, Response, SlaveRequest,
+};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    app_config::{ModbusServerConfig, ModbusServerSource},
+    audit::AuditLog,
+    commands::{write_registers, Origin, WriteError},
+    device::{Device, DeviceError, Devices},
+    poller::Quality,
+    rate_limiter::Priority,
+    write_policy::PolicyViolation,
+};
+
+/// 协议规定一次最多读125个寄存器、2000个线圈，写123个寄存器
+const MAX_REGISTERS: u16 = 125;
+const MAX_BITS: u16 = 2000;
+const MAX_WRITE_REGISTERS: usize = 123;
+
+/// 一段对外地址到设备地址的映射
+struct Mapping {
+    unit_id: u8,
+    device: Arc<Device>,
+    start: u16,
+    /// 不包含
+    end: u32,
+    target_start: u16,
+    source: ModbusServerSource,
+    writable: bool,
+}
+
+impl Mapping {
+    /// 请求的整段地址都在映射里时返回设备上的起始地址
+    fn translate(&self, address: u16, count: u16) -> Option<u16> {
+        let end = address as u32 + count.max(1) as u32;
+        if address < self.start || end > self.end {
+            return None;
+        }
+        let target = self.target_start as u32 + (address - self.start) as u32;
+        (target + count as u32 <= 0x10000).then_some(target as u16)
+    }
+}
+
+/// 作为Modbus TCP服务端，按unit id和地址把请求分发给下游设备
+pub struct ModbusServer {
+    mappings: Vec<Mapping>,
+    audit: Arc<AuditLog>,
+}
+
+impl ModbusServer {
+    pub fn new(
+        config: &ModbusServerConfig,
+        devices: &Devices,
+        audit: Arc<AuditLog>,
+    ) -> Result<Self, String> {
+        let mut mappings: Vec<Mapping> = Vec::new();
+        for mapping in &config.mappings {
+            let device = devices.get(&mapping.device).ok_or_else(|| {
+                format!(
+                    "Modbus服务端unit {}映射的设备{}不存在",
+                    mapping.unit_id, mapping.device
+                )
+            })?;
+            let end = mapping.start as u32 + mapping.count;
+            if mapping.count == 0 || end > 0x10000 {
+                return Err(format!(
+                    "Modbus服务端unit {}的地址范围{}+{}不合法",
+                    mapping.unit_id, mapping.start, mapping.count
+                ));
+            }
+            if let Some(other) = mappings.iter().find(|other| {
+                other.unit_id == mapping.unit_id
+                    && (mapping.start as u32) < other.end
+                    && (other.start as u32) < end
+            }) {
+                return Err(format!(
+                    "Modbus服务端unit {}的地址范围{}+{}和设备{}的映射重叠",
+                    mapping.unit_id, mapping.start, mapping.count, other.device.name
+                ));
+            }
+            info!(
+                "Modbus服务端unit {}地址{}+{}映射到设备{}",
+                mapping.unit_id, mapping.start, mapping.count, mapping.device
+            );
+            mappings.push(Mapping {
+                unit_id: mapping.unit_id,
+                device: device.clone(),
+                start: mapping.start,
+                end,
+                target_start: mapping.target_start.unwrap_or(mapping.start),
+                source: mapping.source,
+                writable: mapping.writable,
+            });
+        }
+        Ok(ModbusServer { mappings, audit })
+    }
+
+    /// 找到请求对应的映射和设备上的地址
+    fn route(
+        &self,
+        unit_id: u8,
+        address: u16,
+        count: u16,
+    ) -> Result<(&Mapping, u16), ExceptionCode> {
+        let mut units = self
+            .mappings
+            .iter()
+            .filter(|mapping| mapping.unit_id == unit_id)
+            .peekable();
+        if units.peek().is_none() {
+            return Err(ExceptionCode::GatewayPathUnavailable);
+        }
+        units
+            .find_map(|mapping| Some((mapping, mapping.translate(address, count)?)))
+            .ok_or(ExceptionCode::IllegalDataAddress)
+    }
+
+    async fn handle(
+        &self,
+        peer: SocketAddr,
+        request: SlaveRequest<'static>,
+    ) -> Result<Response, ExceptionCode> {
+        let unit_id = request.slave;
+        let (count, max) = match request.request {
+            Request::ReadHoldingRegisters(_, count) | Request::ReadInputRegisters(_, count) => {
+                (count, MAX_REGISTERS)
+            }
+            Request::ReadCoils(_, count) | Request::ReadDiscreteInputs(_, count) => {
+                (count, MAX_BITS)
+            }
+            _ => (1, 1),
+        };
+        if count == 0 || count > max {
+            return Err(ExceptionCode::IllegalDataValue);
+        }
+        match request.request {
+            Request::ReadHoldingRegisters(address, count) => {
+                let (mapping, target) = self.route(unit_id, address, count)?;
+                if mapping.source == ModbusServerSource::Cache {
+                    if let Some(values) = cached(&mapping.device, target, count)? {
+                        return Ok(Response::ReadHoldingRegisters(values));
+                    }
+                }
+                forward(
+                    &mapping.device,
+                    Request::ReadHoldingRegisters(target, count),
+                )
+                .await
+            }
+            Request::ReadInputRegisters(address, count) => {
+                let (mapping, target) = self.route(unit_id, address, count)?;
+                forward(&mapping.device, Request::ReadInputRegisters(target, count)).await
+            }
+            Request::ReadCoils(address, count) => {
+                let (mapping, target) = self.route(unit_id, address, count)?;
+                forward(&mapping.device, Request::ReadCoils(target, count)).await
+            }
+            Request::ReadDiscreteInputs(address, count) => {
+                let (mapping, target) = self.route(unit_id, address, count)?;
+                forward(&mapping.device, Request::ReadDiscreteInputs(target, count)).await
+            }
+            Request::WriteSingleRegister(address, value) => {
+                self.write(peer, unit_id, address, vec![value]).await?;
+                Ok(Response::WriteSingleRegister(address, value))
+            }
+            Request::WriteMultipleRegisters(address, values) => {
+                let count = values.len() as u16;
+                self.write(peer, unit_id, address, values.into_owned())
+                    .await?;
+                Ok(Response::WriteMultipleRegisters(address, count))
+            }
+            _ => Err(ExceptionCode::IllegalFunction),
+        }
+    }
+
+    /// 写入和HTTP接口一样经过写入策略检查并记审计日志
+    async fn write(
+        &self,
+        peer: SocketAddr,
+        unit_id: u8,
+        address: u16,
+        values: Vec<u16>,
+    ) -> Result<(), ExceptionCode> {
+        if values.is_empty() || values.len() > MAX_WRITE_REGISTERS {
+            return Err(ExceptionCode::IllegalDataValue);
+        }
+        let (mapping, target) = self.route(unit_id, address, values.len() as u16)?;
+        if !mapping.writable {
+            return Err(ExceptionCode::IllegalFunction);
+        }
+        let origin = Origin {
+            client_ip: Some(peer.ip().to_string()),
+            identity: Some("modbus-server".to_string()),
+            ..Default::default()
+        };
+        write_registers(&mapping.device, target, values, &origin, &self.audit)
+            .await
+            .map_err(|err| match err {
+                WriteError::Policy(violation) => policy_exception(&violation),
+                WriteError::Device(err) => device_exception(err),
+            })
+    }
+}
+
+/// 缓存里有全部寄存器时返回缓存的值；有寄存器质量为bad时说明设备读不到
+fn cached(device: &Device, address: u16, count: u16) -> Result<Option<Vec<u16>>, ExceptionCode> {
+    let mut values = Vec::with_capacity(count as usize);
+    // 地址段可能一直到0xFFFF，用u32算结束地址
+    for register in address as u32..address as u32 + count as u32 {
+        match device.cache.get(register as u16) {
+            Some(sample) if sample.quality == Quality::Bad => {
+                return Err(ExceptionCode::GatewayTargetDevice)
+            }
+            Some(sample) => values.push(sample.value),
+            // 没有轮询的寄存器实时读
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(values))
+}
+
+async fn forward(device: &Device, request: Request<'static>) -> Result<Response, ExceptionCode> {
+    device
+        .call(Priority::Interactive, request)
+        .await
+        .map_err(device_exception)
+}
+
+/// 设备返回的异常码原样返回，连不上、超时等返回网关目标设备无响应
+pub fn device_exception(err: DeviceError) -> ExceptionCode {
+    match err {
+        DeviceError::Exception(code) => code,
+        DeviceError::RateLimited { .. } => ExceptionCode::ServerDeviceBusy,
+        err => {
+            debug!("转发Modbus请求失败：{}", err);
+            ExceptionCode::GatewayTargetDevice
+        }
+    }
+}
+
+pub fn policy_exception(violation: &PolicyViolation) -> ExceptionCode {
+    match violation {
+        PolicyViolation::ReadOnly | PolicyViolation::MaskWriteLimited { .. } => {
+            ExceptionCode::IllegalFunction
+        }
+        PolicyViolation::AddressOverflow { .. } | PolicyViolation::AddressNotAllowed { .. } => {
+            ExceptionCode::IllegalDataAddress
+        }
+        PolicyViolation::OutOfRange { .. } => ExceptionCode::IllegalDataValue,
+        PolicyViolation::Interlock { .. } => ExceptionCode::ServerDeviceFailure,
+    }
+}
+
+/// 一个客户端连接
+struct Connection {
+    server: Arc<ModbusServer>,
+    peer: SocketAddr,
+}
+
+impl Service for Connection {
+    type Request = SlaveRequest<'static>;
+    type Response = Response;
+    type Exception = ExceptionCode;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, ExceptionCode>> + Send>>;
+
+    fn call(&self, request: Self::Request) -> Self::Future {
+        let server = self.server.clone();
+        let peer = self.peer;
+        Box::pin(async move { server.handle(peer, request).await })
+    }
+}
+
+/// 接受Modbus TCP连接，直到进程退出
+pub async fn serve(server: Arc<ModbusServer>, listener: TcpListener) {
+    let on_connected = |stream, peer: SocketAddr| {
+        let server = server.clone();
+        async move {
+            info!("Modbus服务端接受连接{}", peer);
+            Ok(Some((Connection { server, peer }, stream)))
+        }
+    };
+    let on_process_error = |err: io::Error| warn!("Modbus服务端连接出错：{}", err);
+    if let Err(err) = Server::new(listener)
+        .serve(&on_connected, on_process_error)
+        .await
+    {
+        error!("Modbus服务端停止监听：{}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Local;
+    use tokio_modbus::{
+        client::{Context, Reader, Writer},
+        prelude::{tcp, SlaveContext},
+        Slave,
+    };
+
+    use super::*;
+    use crate::test_support::{self, audit_log, from_toml, start_simulator, Simulator};
+
+    /// 启动连到模拟设备的服务端，返回客户端连接、设备和审计日志
+    async fn start(simulator: Simulator, name: &str) -> (Context, Arc<Device>, Arc<AuditLog>) {
+        let devices = test_support::devices(&format!(
+            r#"
+            address = "{}"
+            slave_id = 1
+            name = "main"
+            write_policy = {{ allowed = [[0, 5]] }}
+            "#,
+            start_simulator(simulator).await
+        ));
+        let config: ModbusServerConfig = from_toml(
+            r#"
+            listen = "127.0.0.1:0"
+            mappings = [
+                { unit_id = 1, device = "main", start = 100, count = 10, target_start = 0, source = "live", writable = true },
+                { unit_id = 2, device = "main", source = "live" },
+                { unit_id = 3, device = "main", start = 65530, count = 6, target_start = 10, source = "live" },
+                { unit_id = 4, device = "main", count = 10, target_start = 65534, source = "live" },
+                { unit_id = 5, device = "main" },
+            ]
+            "#,
+        );
+        let audit = audit_log(name);
+        let server = ModbusServer::new(&config, &devices, audit.clone()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        actix_web::rt::spawn(serve(Arc::new(server), listener));
+        let context = tcp::connect_slave(address, Slave(1)).await.unwrap();
+        (context, devices["main"].clone(), audit)
+    }
+
+    async fn read(
+        context: &mut Context,
+        unit_id: u8,
+        address: u16,
+        count: u16,
+    ) -> Result<Vec<u16>, ExceptionCode> {
+        context.set_slave(Slave(unit_id));
+        context
+            .read_holding_registers(address, count)
+            .await
+            .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn remaps_addresses_up_to_the_end_of_the_address_space() {
+        let (mut context, _, _) = start(Simulator::default(), "server-remap").await;
+        // 模拟设备的寄存器值等于设备上的地址
+        assert_eq!(read(&mut context, 1, 100, 2).await, Ok(vec![0, 1]));
+        assert_eq!(read(&mut context, 1, 109, 1).await, Ok(vec![9]));
+        assert_eq!(
+            read(&mut context, 1, 109, 2).await,
+            Err(ExceptionCode::IllegalDataAddress)
+        );
+        assert_eq!(
+            read(&mut context, 1, 99, 1).await,
+            Err(ExceptionCode::IllegalDataAddress)
+        );
+
+        assert_eq!(
+            read(&mut context, 2, 65534, 2).await,
+            Ok(vec![65534, 65535])
+        );
+        assert_eq!(read(&mut context, 3, 65535, 1).await, Ok(vec![15]));
+        assert_eq!(
+            read(&mut context, 3, 65535, 2).await,
+            Err(ExceptionCode::IllegalDataAddress)
+        );
+        // 映射到设备上的地址超过0xFFFF
+        assert_eq!(read(&mut context, 4, 0, 2).await, Ok(vec![65534, 65535]));
+        assert_eq!(
+            read(&mut context, 4, 0, 3).await,
+            Err(ExceptionCode::IllegalDataAddress)
+        );
+
+        assert_eq!(
+            read(&mut context, 9, 0, 1).await,
+            Err(ExceptionCode::GatewayPathUnavailable)
+        );
+    }
+
+    #[actix_web::test]
+    async fn enforces_protocol_count_limits() {
+        let (mut context, _, _) = start(Simulator::default(), "server-limits").await;
+        assert_eq!(
+            read(&mut context, 2, 0, 125)
+                .await
+                .map(|values| values.len()),
+            Ok(125)
+        );
+        assert_eq!(
+            read(&mut context, 2, 0, 126).await,
+            Err(ExceptionCode::IllegalDataValue)
+        );
+        assert_eq!(
+            read(&mut context, 2, 0, 0).await,
+            Err(ExceptionCode::IllegalDataValue)
+        );
+        context.set_slave(Slave(2));
+        assert_eq!(
+            context.read_input_registers(0, 126).await.unwrap(),
+            Err(ExceptionCode::IllegalDataValue)
+        );
+        assert_eq!(
+            context.read_coils(0, 2001).await.unwrap(),
+            Err(ExceptionCode::IllegalDataValue)
+        );
+        assert_eq!(
+            context.read_discrete_inputs(0, 2001).await.unwrap(),
+            Err(ExceptionCode::IllegalDataValue)
+        );
+    }
+
+    #[actix_web::test]
+    async fn answers_from_the_cache_when_every_register_is_polled() {
+        let simulator = Simulator::default();
+        let (mut context, device, _) = start(simulator.clone(), "server-cache").await;
+        device.cache.update(0, &[7, 8], Local::now());
+
+        assert_eq!(read(&mut context, 5, 0, 2).await, Ok(vec![7, 8]));
+        assert!(simulator.unit_ids.lock().unwrap().is_empty());
+        // 有寄存器没轮询时实时读
+        assert_eq!(read(&mut context, 5, 0, 3).await, Ok(vec![0, 1, 2]));
+        assert_eq!(simulator.unit_ids.lock().unwrap().len(), 1);
+
+        device.cache.mark_bad(0, 1);
+        assert_eq!(
+            read(&mut context, 5, 0, 2).await,
+            Err(ExceptionCode::GatewayTargetDevice)
+        );
+        assert_eq!(simulator.unit_ids.lock().unwrap().len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn writes_go_through_the_write_policy_and_audit() {
+        let simulator = Simulator::default();
+        let (mut context, _, audit) = start(simulator.clone(), "server-write").await;
+        context.set_slave(Slave(1));
+        assert_eq!(
+            context.write_single_register(101, 42).await.unwrap(),
+            Ok(())
+        );
+        // 设备地址6不在允许范围内
+        assert_eq!(
+            context.write_single_register(106, 1).await.unwrap(),
+            Err(ExceptionCode::IllegalDataAddress)
+        );
+        // 没有开启写入的映射
+        context.set_slave(Slave(2));
+        assert_eq!(
+            context.write_single_register(1, 1).await.unwrap(),
+            Err(ExceptionCode::IllegalFunction)
+        );
+
+        assert_eq!(
+            *simulator.writes.lock().unwrap(),
+            [Request::WriteSingleRegister(1, 42)]
+        );
+        let records = audit.recent(10);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].address, 6);
+        assert_eq!(records[0].outcome, "rejected: 地址6不允许写入");
+        assert_eq!(records[0].identity.as_deref(), Some("modbus-server"));
+        assert_eq!(records[1].address, 1);
+        assert_eq!(records[1].old_values, Some(vec![1]));
+        assert_eq!(records[1].outcome, "success");
+    }
+}
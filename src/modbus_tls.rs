@@ -0,0 +1,228 @@
+use std::{io, net::SocketAddr, sync::Arc};
+
+use rustls::{
+    crypto::ring::default_provider,
+    pki_types::{CertificateDer, ServerName},
+    ClientConfig,
+};
+use tokio::net::TcpStream;
+use tokio_rustls::{client::TlsStream, TlsConnector};
+use tracing::debug;
+use x509_parser::{der_parser::parse_der, prelude::*};
+
+use crate::app_config::ModbusTlsConfig;
+use crate::tls::{load_certs, load_private_key, load_roots};
+
+/// Modbus/TCP Security规范里证书扩展RoleOID，值是UTF8String
+const ROLE_OID: &str = "1.3.6.1.4.1.50316.802.1";
+
+/// 取出证书里的Modbus角色，没有这个扩展时返回None
+pub fn certificate_role(cert: &CertificateDer<'_>) -> Result<Option<String>, String> {
+    let (_, cert) =
+        X509Certificate::from_der(cert).map_err(|err| format!("解析证书失败：{}", err))?;
+    let Some(extension) = cert
+        .extensions()
+        .iter()
+        .find(|extension| extension.oid.to_id_string() == ROLE_OID)
+    else {
+        return Ok(None);
+    };
+    let (_, value) =
+        parse_der(extension.value).map_err(|err| format!("解析角色扩展失败：{}", err))?;
+    value
+        .as_str()
+        .map(|role| Some(role.to_string()))
+        .map_err(|err| format!("角色扩展不是字符串：{}", err))
+}
+
+/// 一个设备的TLS连接参数，连接池每次新建连接时使用
+pub struct ModbusTls {
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+    /// 客户端证书里的角色，设备端按这个角色授权
+    pub role: Option<String>,
+}
+
+impl ModbusTls {
+    pub fn new(config: &ModbusTlsConfig, address: &str) -> Result<Self, String> {
+        let certs = load_certs(&config.cert_file)?;
+        let role = certificate_role(&certs[0])?;
+        let client_config = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|err| format!("创建TLS配置失败：{}", err))?
+            .with_root_certificates(load_roots(&config.ca_file)?)
+            .with_client_auth_cert(certs, load_private_key(&config.key_file)?)
+            .map_err(|err| format!("客户端证书无效：{}", err))?;
+        let server_name = match &config.server_name {
+            Some(name) => name.clone(),
+            None => address
+                .parse::<SocketAddr>()
+                .map_err(|err| format!("地址{}无效：{}", address, err))?
+                .ip()
+                .to_string(),
+        };
+        Ok(ModbusTls {
+            connector: TlsConnector::from(Arc::new(client_config)),
+            server_name: ServerName::try_from(server_name.clone())
+                .map_err(|err| format!("server_name {}无效：{}", server_name, err))?,
+            role,
+        })
+    }
+
+    pub async fn connect(&self, socket_addr: SocketAddr) -> io::Result<TlsStream<TcpStream>> {
+        let stream = TcpStream::connect(socket_addr).await?;
+        let stream = self
+            .connector
+            .connect(self.server_name.clone(), stream)
+            .await?;
+        if let Some(cert) = stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+        {
+            match certificate_role(cert) {
+                Ok(role) => debug!("modbus:{}的服务端证书角色：{:?}", socket_addr, role),
+                Err(err) => debug!("modbus:{}的服务端证书无法解析：{}", socket_addr, err),
+            }
+        }
+        Ok(stream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{future, time::Duration};
+
+    use actix_web::rt::time::timeout;
+    use rustls::{server::WebPkiClientVerifier, ServerConfig};
+    use tokio::net::TcpListener;
+    use tokio_modbus::{
+        client::Reader,
+        prelude::tcp,
+        server::{tcp::Server, Service},
+        ExceptionCode, Request, Response, Slave,
+    };
+    use tokio_rustls::TlsAcceptor;
+
+    use super::*;
+
+    fn fixture(name: &str) -> String {
+        format!(
+            "{}/testdata/modbus_tls/{}",
+            env!("CARGO_MANIFEST_DIR"),
+            name
+        )
+    }
+
+    /// 保持寄存器的值等于地址
+    struct Simulator;
+
+    impl Service for Simulator {
+        type Request = Request<'static>;
+        type Response = Response;
+        type Exception = ExceptionCode;
+        type Future = future::Ready<Result<Response, ExceptionCode>>;
+
+        fn call(&self, request: Self::Request) -> Self::Future {
+            future::ready(match request {
+                Request::ReadHoldingRegisters(address, count) => Ok(
+                    Response::ReadHoldingRegisters((address..address + count).collect()),
+                ),
+                _ => Err(ExceptionCode::IllegalFunction),
+            })
+        }
+    }
+
+    /// 启动一个TLS的模拟设备，只接受客户端证书角色为`role`的连接
+    async fn start_simulator(role: &'static str) -> SocketAddr {
+        let provider = Arc::new(default_provider());
+        let verifier = WebPkiClientVerifier::builder_with_provider(
+            Arc::new(load_roots(&fixture("ca.pem")).unwrap()),
+            provider.clone(),
+        )
+        .build()
+        .unwrap();
+        let config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(
+                load_certs(&fixture("server.pem")).unwrap(),
+                load_private_key(&fixture("server.key")).unwrap(),
+            )
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        actix_web::rt::spawn(async move {
+            let on_connected = |stream, _| {
+                let acceptor = acceptor.clone();
+                async move {
+                    let stream = acceptor.accept(stream).await?;
+                    let client_role = match stream.get_ref().1.peer_certificates() {
+                        Some([cert, ..]) => certificate_role(cert).map_err(io::Error::other)?,
+                        _ => None,
+                    };
+                    Ok((client_role.as_deref() == Some(role)).then_some((Simulator, stream)))
+                }
+            };
+            Server::new(listener).serve(&on_connected, |_| {}).await
+        });
+        address
+    }
+
+    fn client(name: &str, address: SocketAddr) -> ModbusTls {
+        let config = ModbusTlsConfig {
+            ca_file: fixture("ca.pem"),
+            cert_file: fixture(&format!("{}.pem", name)),
+            key_file: fixture(&format!("{}.key", name)),
+            server_name: None,
+        };
+        ModbusTls::new(&config, &address.to_string()).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn reads_over_mutual_tls_with_the_configured_role() {
+        let address = start_simulator("operator").await;
+        let tls = client("operator", address);
+        assert_eq!(tls.role.as_deref(), Some("operator"));
+
+        let mut context = tcp::attach_slave(tls.connect(address).await.unwrap(), Slave(1));
+        let values = timeout(
+            Duration::from_secs(5),
+            context.read_holding_registers(10, 3),
+        )
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+        assert_eq!(values, vec![10, 11, 12]);
+    }
+
+    #[actix_web::test]
+    async fn device_rejects_other_roles() {
+        let address = start_simulator("operator").await;
+        let tls = client("viewer", address);
+        assert_eq!(tls.role.as_deref(), Some("viewer"));
+
+        // TLS 1.3里客户端可能在服务端校验完证书之前就认为握手成功了，被拒绝时第一次读失败
+        let result = match tls.connect(address).await {
+            Ok(stream) => {
+                let mut context = tcp::attach_slave(stream, Slave(1));
+                timeout(Duration::from_secs(5), context.read_holding_registers(0, 1))
+                    .await
+                    .unwrap()
+                    .map(|_| ())
+            }
+            Err(err) => Err(err.into()),
+        };
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn certificate_without_role_extension() {
+        let certs = load_certs(&fixture("server.pem")).unwrap();
+        assert_eq!(certificate_role(&certs[0]).unwrap(), None);
+    }
+}
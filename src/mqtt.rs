@@ -0,0 +1,631 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use actix_web::rt::time::{interval, sleep};
+use chrono::Local;
+use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Packet, Publish, QoS};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    app_config::{MqttConfig, MqttPayload, MqttPublishMode},
+    audit::AuditLog,
+    commands::{write_registers, Origin},
+    device::Devices,
+    poller::{self, Quality, TagValue},
+};
+
+/// 发给broker的请求队列长度，broker断开时最多积压这么多
+const REQUEST_CAPACITY: usize = 256;
+
+/// json模式的消息
+#[derive(Serialize)]
+struct TagMessage<'a> {
+    device: &'a str,
+    #[serde(flatten)]
+    value: &'a TagValue,
+}
+
+/// sparkplug模式的消息，字段名沿用Sparkplug B
+#[derive(Serialize)]
+struct SparkplugPayload {
+    timestamp: i64,
+    metrics: Vec<SparkplugMetric>,
+    seq: u8,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SparkplugMetric {
+    name: String,
+    timestamp: Option<i64>,
+    data_type: &'static str,
+    value: Option<u16>,
+    is_null: bool,
+    properties: SparkplugProperties,
+}
+
+#[derive(Serialize)]
+struct SparkplugProperties {
+    quality: Quality,
+    unit: Option<String>,
+}
+
+/// 命令主题收到的值，可以是数字或`{"value": 数字}`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum CommandPayload {
+    Value(u16),
+    Object { value: u16 },
+}
+
+#[derive(Serialize)]
+struct CommandResult {
+    success: bool,
+    error: String,
+}
+
+pub struct MqttBridge {
+    config: MqttConfig,
+    qos: QoS,
+    client: AsyncClient,
+    devices: Devices,
+    audit: Arc<AuditLog>,
+}
+
+impl MqttBridge {
+    pub fn new(
+        config: &MqttConfig,
+        devices: Devices,
+        audit: Arc<AuditLog>,
+    ) -> Result<(Self, EventLoop), String> {
+        let qos = rumqttc::qos(config.qos).map_err(|_| format!("MQTT qos {}不合法", config.qos))?;
+        if let Some(command_topic) = &config.command_topic {
+            let segments: Vec<&str> = command_topic.split('/').collect();
+            if !segments.contains(&"{device}") || !segments.contains(&"{tag}") {
+                return Err(format!(
+                    "MQTT命令主题{}必须包含完整一级的{{device}}和{{tag}}",
+                    command_topic
+                ));
+            }
+            // 命令主题不能收到网关自己发布的数据和命令结果，否则会把它们当成命令执行
+            let command = wildcard(command_topic);
+            let published = match config.payload {
+                MqttPayload::Json => wildcard(&config.topic),
+                MqttPayload::Sparkplug => {
+                    format!("spBv1.0/{}/DDATA/{}/+", config.group_id, config.node_id)
+                }
+            };
+            for topic in [published, format!("{}/result", command)] {
+                if filters_overlap(&command, &topic) {
+                    return Err(format!(
+                        "MQTT命令主题{}和网关发布的主题{}重叠",
+                        command_topic, topic
+                    ));
+                }
+            }
+        }
+        let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+        options.set_keep_alive(Duration::from_secs(config.keep_alive_secs.max(5)));
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            options.set_credentials(username, password);
+        }
+        let (client, event_loop) = AsyncClient::new(options, REQUEST_CAPACITY);
+        Ok((
+            MqttBridge {
+                config: config.clone(),
+                qos,
+                client,
+                devices,
+                audit,
+            },
+            event_loop,
+        ))
+    }
+
+    fn topic(&self, device: &str, tag: &str) -> String {
+        self.config
+            .topic
+            .replace("{device}", device)
+            .replace("{tag}", tag)
+    }
+
+    /// Sparkplug B的设备数据主题
+    fn sparkplug_topic(&self, device: &str) -> String {
+        format!(
+            "spBv1.0/{}/DDATA/{}/{}",
+            self.config.group_id, self.config.node_id, device
+        )
+    }
+
+    /// 命令主题的订阅
+    fn command_filter(&self) -> Option<String> {
+        Some(wildcard(self.config.command_topic.as_ref()?))
+    }
+
+    /// 从命令主题里取出设备名和数据点名
+    fn parse_command_topic<'a>(&self, topic: &'a str) -> Option<(&'a str, &'a str)> {
+        let template = self.config.command_topic.as_ref()?;
+        let segments: Vec<&str> = topic.split('/').collect();
+        let templates: Vec<&str> = template.split('/').collect();
+        if segments.len() != templates.len() {
+            return None;
+        }
+        let (mut device, mut tag) = (None, None);
+        for (segment, template) in segments.into_iter().zip(templates) {
+            match template {
+                "{device}" => device = Some(segment),
+                "{tag}" => tag = Some(segment),
+                _ if segment != template => return None,
+                _ => {}
+            }
+        }
+        device.zip(tag)
+    }
+
+    fn publish(&self, topic: String, payload: Vec<u8>) -> bool {
+        self.publish_with_retain(topic, payload, self.config.retain)
+    }
+
+    fn publish_with_retain(&self, topic: String, payload: Vec<u8>, retain: bool) -> bool {
+        match self.client.try_publish(topic, self.qos, retain, payload) {
+            Ok(()) => true,
+            Err(err) => {
+                debug!("MQTT发布失败：{}", err);
+                false
+            }
+        }
+    }
+
+    /// 命令经过和HTTP写接口一样的写入策略检查和审计，结果发到`{命令主题}/result`。
+    /// 命令主题按QoS 0订阅，broker不会重发，命令最多执行一次；没收到结果的命令由发送方决定是否重发
+    async fn handle_command(&self, publish: Publish) {
+        // 保留消息是broker存着的旧命令，执行它会重复写
+        if publish.retain {
+            warn!("忽略MQTT保留消息里的命令{}", publish.topic);
+            return;
+        }
+        let Some((device_name, tag_name)) = self.parse_command_topic(&publish.topic) else {
+            return;
+        };
+        let result = match (
+            self.devices.get(device_name).and_then(|device| {
+                let tag = device.tags.iter().find(|tag| tag.name == tag_name)?;
+                Some((device, tag))
+            }),
+            serde_json::from_slice::<CommandPayload>(&publish.payload),
+        ) {
+            (None, _) => Err(format!("设备{}没有数据点{}", device_name, tag_name)),
+            (_, Err(err)) => Err(format!("命令格式错误：{}", err)),
+            (Some((device, tag)), Ok(payload)) => {
+                let value = match payload {
+                    CommandPayload::Value(value) | CommandPayload::Object { value } => value,
+                };
+                info!(
+                    "MQTT命令：设备{}数据点{}写入{}",
+                    device_name, tag_name, value
+                );
+                let origin = Origin {
+                    identity: Some(format!("mqtt:{}", self.config.client_id)),
+                    ..Default::default()
+                };
+                write_registers(device, tag.register, vec![value], &origin, &self.audit)
+                    .await
+                    .map_err(|err| err.to_string())
+            }
+        };
+        if let Err(err) = &result {
+            warn!("MQTT命令{}失败：{}", publish.topic, err);
+        }
+        let response = CommandResult {
+            success: result.is_ok(),
+            error: result.err().unwrap_or_default(),
+        };
+        // 结果不保留，否则以后订阅的客户端会收到旧命令的结果
+        self.publish_with_retain(
+            format!("{}/result", publish.topic),
+            serde_json::to_vec(&response).unwrap(),
+            false,
+        );
+    }
+
+    fn tag_message(&self, device: &str, value: &TagValue) -> Vec<u8> {
+        serde_json::to_vec(&TagMessage { device, value }).unwrap()
+    }
+
+    fn sparkplug_message(&self, values: &[TagValue], seq: u8) -> Vec<u8> {
+        let payload = SparkplugPayload {
+            timestamp: Local::now().timestamp_millis(),
+            metrics: values
+                .iter()
+                .map(|value| SparkplugMetric {
+                    name: value.name.clone(),
+                    timestamp: value.timestamp.map(|t| t.timestamp_millis()),
+                    data_type: "UInt16",
+                    value: value.value,
+                    is_null: value.value.is_none(),
+                    properties: SparkplugProperties {
+                        quality: value.quality,
+                        unit: value.unit.clone(),
+                    },
+                })
+                .collect(),
+            seq,
+        };
+        serde_json::to_vec(&payload).unwrap()
+    }
+}
+
+/// 主题模板里的`{device}`、`{tag}`换成通配符+
+fn wildcard(template: &str) -> String {
+    template.replace("{device}", "+").replace("{tag}", "+")
+}
+
+/// 两个主题过滤器能不能匹配到同一个主题
+fn filters_overlap(a: &str, b: &str) -> bool {
+    let (mut a, mut b) = (a.split('/'), b.split('/'));
+    loop {
+        match (a.next(), b.next()) {
+            (Some("#"), _) | (_, Some("#")) => return true,
+            (Some(x), Some(y)) if x == y || x == "+" || y == "+" => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// 驱动MQTT连接，断开后自动重连，收到命令时处理
+pub async fn connection_loop(bridge: Arc<MqttBridge>, mut event_loop: EventLoop) {
+    loop {
+        match event_loop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("MQTT连接{}:{}成功", bridge.config.host, bridge.config.port);
+                if let Some(filter) = bridge.command_filter() {
+                    if let Err(err) = bridge.client.try_subscribe(&filter, QoS::AtMostOnce) {
+                        error!("订阅MQTT命令主题{}失败：{}", filter, err);
+                    }
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                let bridge = bridge.clone();
+                actix_web::rt::spawn(async move { bridge.handle_command(publish).await });
+            }
+            Ok(_) => {}
+            Err(err) => {
+                warn!(
+                    "MQTT连接{}:{}失败：{}，1秒后重连",
+                    bridge.config.host, bridge.config.port, err
+                );
+                sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+/// 按配置周期发布数据点，on_change时只发布值或质量变化的
+pub async fn publish_loop(bridge: Arc<MqttBridge>) {
+    let mut ticker = interval(Duration::from_millis(bridge.config.interval_ms.max(100)));
+    // 上一次发布成功的(值, 质量)
+    let mut published: HashMap<(String, String), (Option<u16>, Quality)> = HashMap::new();
+    let mut seq: u8 = 0;
+    loop {
+        ticker.tick().await;
+        for device in bridge.devices.values() {
+            let values: Vec<TagValue> = device
+                .tags
+                .iter()
+                .map(|tag| poller::tag_value(device, tag))
+                .filter(|value| value.timestamp.is_some())
+                .filter(|value| {
+                    bridge.config.mode == MqttPublishMode::Periodic
+                        || published.get(&(device.name.clone(), value.name.clone()))
+                            != Some(&(value.value, value.quality))
+                })
+                .collect();
+            if values.is_empty() {
+                continue;
+            }
+            let sent: Vec<&TagValue> = match bridge.config.payload {
+                MqttPayload::Json => values
+                    .iter()
+                    .filter(|value| {
+                        bridge.publish(
+                            bridge.topic(&device.name, &value.name),
+                            bridge.tag_message(&device.name, value),
+                        )
+                    })
+                    .collect(),
+                MqttPayload::Sparkplug => {
+                    let message = bridge.sparkplug_message(&values, seq);
+                    if bridge.publish(bridge.sparkplug_topic(&device.name), message) {
+                        seq = seq.wrapping_add(1);
+                        values.iter().collect()
+                    } else {
+                        Vec::new()
+                    }
+                }
+            };
+            for value in sent {
+                published.insert(
+                    (device.name.clone(), value.name.clone()),
+                    (value.value, value.quality),
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, Read, Write},
+        net::{TcpListener, TcpStream},
+        sync::Mutex,
+        thread,
+        time::Instant,
+    };
+
+    use actix_web::rt::spawn;
+    use tokio_modbus::Request;
+
+    use super::*;
+    use crate::{
+        app_config::HistoryConfig,
+        history::History,
+        test_support::{self, audit_log, start_simulator, temp_dir, Simulator},
+    };
+
+    /// 只实现网关用到的MQTT 3.1.1报文的broker，记录客户端发布的消息
+    #[derive(Default)]
+    struct Broker {
+        /// (连接, 过滤器, 订阅的QoS)
+        subscribers: Mutex<Vec<(TcpStream, String, u8)>>,
+        published: Mutex<Vec<(String, String)>>,
+        /// 带retain标志发布过的主题
+        retained: Mutex<Vec<String>>,
+    }
+
+    impl Broker {
+        fn start() -> (Arc<Broker>, u16) {
+            let broker = Arc::new(Broker::default());
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let accepting = broker.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    let broker = accepting.clone();
+                    thread::spawn(move || broker.serve(stream));
+                }
+            });
+            (broker, port)
+        }
+
+        fn serve(&self, mut stream: TcpStream) {
+            while let Ok((header, body)) = read_packet(&mut stream) {
+                let reply = match header >> 4 {
+                    // CONNECT
+                    1 => vec![0x20, 0x02, 0x00, 0x00],
+                    // PUBLISH，QoS 1回PUBACK
+                    3 => {
+                        let len = u16::from_be_bytes([body[0], body[1]]) as usize;
+                        let topic = String::from_utf8_lossy(&body[2..2 + len]).into_owned();
+                        let (payload, reply) = match (header >> 1) & 3 {
+                            0 => (&body[2 + len..], Vec::new()),
+                            _ => (
+                                &body[4 + len..],
+                                vec![0x40, 0x02, body[2 + len], body[3 + len]],
+                            ),
+                        };
+                        let payload = String::from_utf8_lossy(payload).into_owned();
+                        if header & 1 != 0 {
+                            self.retained.lock().unwrap().push(topic.clone());
+                        }
+                        self.published.lock().unwrap().push((topic, payload));
+                        reply
+                    }
+                    // SUBSCRIBE，只支持一个过滤器
+                    8 => {
+                        let len = u16::from_be_bytes([body[2], body[3]]) as usize;
+                        let filter = String::from_utf8_lossy(&body[4..4 + len]).into_owned();
+                        let qos = body[4 + len];
+                        let subscriber = stream.try_clone().unwrap();
+                        self.subscribers
+                            .lock()
+                            .unwrap()
+                            .push((subscriber, filter, qos));
+                        vec![0x90, 0x03, body[0], body[1], qos]
+                    }
+                    // PINGREQ
+                    12 => vec![0xd0, 0x00],
+                    _ => return,
+                };
+                if stream.write_all(&reply).is_err() {
+                    return;
+                }
+            }
+        }
+
+        /// 以QoS 0发给订阅了匹配主题的客户端
+        fn deliver(&self, topic: &str, payload: &str, retain: bool) {
+            let mut body = (topic.len() as u16).to_be_bytes().to_vec();
+            body.extend_from_slice(topic.as_bytes());
+            body.extend_from_slice(payload.as_bytes());
+            let mut packet = vec![0x30 | retain as u8, body.len() as u8];
+            packet.extend_from_slice(&body);
+            for (stream, filter, _) in self.subscribers.lock().unwrap().iter_mut() {
+                if filters_overlap(filter, topic) {
+                    stream.write_all(&packet).unwrap();
+                }
+            }
+        }
+
+        async fn wait_for(&self, check: impl Fn(&Broker) -> bool) {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while !check(self) {
+                assert!(Instant::now() < deadline, "等待broker超时");
+                sleep(Duration::from_millis(20)).await;
+            }
+        }
+
+        fn messages(&self, topic: &str) -> Vec<String> {
+            self.published
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(t, _)| t == topic)
+                .map(|(_, payload)| payload.clone())
+                .collect()
+        }
+    }
+
+    fn read_packet(stream: &mut TcpStream) -> io::Result<(u8, Vec<u8>)> {
+        let mut byte = [0u8];
+        stream.read_exact(&mut byte)?;
+        let header = byte[0];
+        let (mut len, mut shift) = (0usize, 0);
+        loop {
+            stream.read_exact(&mut byte)?;
+            len |= ((byte[0] & 0x7f) as usize) << shift;
+            shift += 7;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = vec![0u8; len];
+        stream.read_exact(&mut body)?;
+        Ok((header, body))
+    }
+
+    fn devices(address: &str) -> Devices {
+        test_support::devices(&format!(
+            r#"
+            address = "{}"
+            slave_id = 1
+            name = "main"
+            poll = {{ interval_ms = 100 }}
+            tags = [{{ name = "speed", register = 1, history = false }}]
+            "#,
+            address
+        ))
+    }
+
+    fn mqtt_config(port: u16, command_topic: &str) -> MqttConfig {
+        MqttConfig {
+            port,
+            client_id: "gateway-test".to_string(),
+            keep_alive_secs: 30,
+            interval_ms: 100,
+            command_topic: Some(command_topic.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[actix_web::test]
+    async fn publishes_values_and_executes_commands() {
+        let simulator = Simulator::default();
+        simulator.registers.lock().unwrap().insert(1, 12);
+        let devices = devices(&start_simulator(simulator.clone()).await);
+        let history = Arc::new(
+            History::open(&HistoryConfig {
+                directory: temp_dir("mqtt-history"),
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+        spawn(poller::poll_loop(devices["main"].clone(), history));
+
+        let (broker, port) = Broker::start();
+        let audit = audit_log("mqtt-audit");
+        let config = MqttConfig {
+            retain: true,
+            ..mqtt_config(port, "plant/{device}/{tag}/set")
+        };
+        let (bridge, event_loop) = MqttBridge::new(&config, devices, audit.clone()).unwrap();
+        let bridge = Arc::new(bridge);
+        spawn(connection_loop(bridge.clone(), event_loop));
+        spawn(publish_loop(bridge));
+
+        broker
+            .wait_for(|broker| {
+                broker
+                    .messages("plant/main/speed")
+                    .iter()
+                    .any(|message| message.contains(r#""value":12"#))
+            })
+            .await;
+        broker
+            .wait_for(|broker| !broker.subscribers.lock().unwrap().is_empty())
+            .await;
+        {
+            let subscribers = broker.subscribers.lock().unwrap();
+            assert_eq!(subscribers[0].1, "plant/+/+/set");
+            // 命令最多执行一次，broker不会重发
+            assert_eq!(subscribers[0].2, 0);
+        }
+
+        // 保留的旧命令不执行
+        broker.deliver("plant/main/speed/set", "7", true);
+        broker.deliver("plant/main/speed/set", r#"{"value": 42}"#, false);
+        broker
+            .wait_for(|broker| !broker.messages("plant/main/speed/set/result").is_empty())
+            .await;
+        assert_eq!(
+            broker.messages("plant/main/speed/set/result"),
+            vec![r#"{"success":true,"error":""}"#.to_string()]
+        );
+        assert_eq!(
+            *simulator.writes.lock().unwrap(),
+            vec![Request::WriteSingleRegister(1, 42)]
+        );
+        // 数据点按配置保留，命令结果不保留
+        let retained = broker.retained.lock().unwrap().clone();
+        assert!(retained.contains(&"plant/main/speed".to_string()));
+        assert!(!retained.contains(&"plant/main/speed/set/result".to_string()));
+        let record = &audit.recent(1)[0];
+        assert_eq!(record.identity.as_deref(), Some("mqtt:gateway-test"));
+        assert_eq!(record.new_values, vec![42]);
+
+        broker.deliver("plant/main/missing/set", "1", false);
+        broker
+            .wait_for(|broker| !broker.messages("plant/main/missing/set/result").is_empty())
+            .await;
+        assert!(broker.messages("plant/main/missing/set/result")[0].contains(r#""success":false"#));
+    }
+
+    #[actix_web::test]
+    async fn command_topic_must_not_overlap_published_topics() {
+        let devices = devices("127.0.0.1:1");
+        let audit = audit_log("mqtt-overlap");
+        for command_topic in [
+            "plant/{device}/{tag}",
+            "plant/{device}/{tag}/#",
+            "+/{device}/{tag}",
+        ] {
+            assert!(
+                MqttBridge::new(
+                    &mqtt_config(1883, command_topic),
+                    devices.clone(),
+                    audit.clone()
+                )
+                .is_err(),
+                "{}",
+                command_topic
+            );
+        }
+        let sparkplug = MqttConfig {
+            payload: MqttPayload::Sparkplug,
+            ..mqtt_config(1883, "spBv1.0/plant/DCMD/modbus-gateway/{device}/{tag}")
+        };
+        assert!(MqttBridge::new(&sparkplug, devices.clone(), audit.clone()).is_ok());
+        let sparkplug = MqttConfig {
+            payload: MqttPayload::Sparkplug,
+            ..mqtt_config(1883, "spBv1.0/{device}/DDATA/{tag}/+")
+        };
+        assert!(MqttBridge::new(&sparkplug, devices.clone(), audit.clone()).is_err());
+        assert!(MqttBridge::new(
+            &mqtt_config(1883, "plant/{device}/{tag}/set"),
+            devices,
+            audit
+        )
+        .is_ok());
+    }
+}
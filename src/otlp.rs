@@ -1,56 +1,126 @@
-use std::sync::LazyLock;
 use std::time::Duration;
 
 use opentelemetry::KeyValue;
 use opentelemetry_sdk::Resource;
 
 use opentelemetry::trace::TraceError;
-use opentelemetry_otlp::{LogExporter, MetricExporter, SpanExporter, WithExportConfig};
+use opentelemetry_otlp::{
+    LogExporter, MetricExporter, Protocol, SpanExporter, WithExportConfig, WithHttpConfig,
+    WithTonicConfig,
+};
 use opentelemetry_sdk::logs::LogError;
 use opentelemetry_sdk::logs::LoggerProvider;
 use opentelemetry_sdk::metrics::MetricError;
-use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider};
-use opentelemetry_sdk::trace;
-
-pub static SERVICE_NAME: LazyLock<Resource> = LazyLock::new(|| {
-    let service_name_resource = Resource::new(vec![KeyValue::new("service.name", "actix_server")]);
-    service_name_resource
-});
-const OTLP_URL: &str = "http://10.39.10.126:4317";
-pub fn init_traces() -> Result<trace::TracerProvider, TraceError> {
-    let exporter = SpanExporter::builder()
-        .with_tonic()
-        .with_endpoint(OTLP_URL)
-        .build()?;
-    Ok(trace::TracerProvider::builder()
-        .with_resource(SERVICE_NAME.clone())
-        // .with_simple_exporter(exporter)
-        .with_batch_exporter(exporter, opentelemetry_sdk::runtime::TokioCurrentThread)
-        .build())
+use opentelemetry_sdk::metrics::PeriodicReader;
+use opentelemetry_sdk::trace::{self, Sampler};
+use tonic::metadata::{MetadataKey, MetadataMap, MetadataValue};
+
+use crate::app_config::{OtlpProtocol, TelemetryConfig};
+
+pub fn resource(config: &TelemetryConfig) -> Resource {
+    let mut attributes = vec![KeyValue::new("service.name", config.service_name.clone())];
+    attributes.extend(
+        config
+            .resource
+            .iter()
+            .map(|(key, value)| KeyValue::new(key.clone(), value.clone())),
+    );
+    Resource::new(attributes)
 }
-pub fn init_metrics() -> Result<opentelemetry_sdk::metrics::SdkMeterProvider, MetricError> {
-    let exporter = MetricExporter::builder()
-        .with_tonic()
-        .with_endpoint(OTLP_URL)
-        .build()?;
-    let reader = PeriodicReader::builder(exporter, opentelemetry_sdk::runtime::TokioCurrentThread)
-        .with_interval(std::time::Duration::from_secs(3))
-        .with_timeout(Duration::from_secs(10))
-        .build();
-
-    Ok(SdkMeterProvider::builder()
-        .with_reader(reader)
-        .with_resource(SERVICE_NAME.clone())
-        .build())
+
+// 同一套配置要分别给span、metric、log三种exporter用，它们的builder类型不同，只能用宏展开；
+// $error把配置错误转换成对应的错误类型
+macro_rules! build_exporter {
+    ($builder:expr, $config:expr, $endpoint:expr, $http_path:expr, $error:expr) => {
+        match $config.protocol {
+            OtlpProtocol::Grpc => $builder
+                .with_tonic()
+                .with_endpoint($endpoint)
+                .with_metadata(metadata(&$config.headers).map_err($error)?)
+                .build(),
+            OtlpProtocol::HttpProtobuf => $builder
+                .with_http()
+                .with_protocol(Protocol::HttpBinary)
+                // http方式下代码里配置的endpoint不会自动加上信号路径
+                .with_endpoint(format!("{}{}", $endpoint.trim_end_matches('/'), $http_path))
+                .with_headers($config.headers.clone())
+                .build(),
+        }
+    };
+}
+
+fn metadata(headers: &std::collections::HashMap<String, String>) -> Result<MetadataMap, String> {
+    let mut map = MetadataMap::new();
+    for (key, value) in headers {
+        match (
+            MetadataKey::from_bytes(key.as_bytes()),
+            MetadataValue::try_from(value.as_str()),
+        ) {
+            (Ok(key), Ok(value)) => {
+                map.insert(key, value);
+            }
+            _ => return Err(format!("无效的OTLP header：{}", key)),
+        }
+    }
+    Ok(map)
 }
-pub fn init_logs() -> Result<opentelemetry_sdk::logs::LoggerProvider, LogError> {
-    let exporter = LogExporter::builder()
-        .with_tonic()
-        .with_endpoint(OTLP_URL)
-        .build()?;
-
-    Ok(LoggerProvider::builder()
-        .with_resource(SERVICE_NAME.clone())
-        .with_batch_exporter(exporter, opentelemetry_sdk::runtime::TokioCurrentThread)
-        .build())
+
+/// 没有配置endpoint或者关闭了traces时返回None
+pub fn init_traces(config: &TelemetryConfig) -> Result<Option<trace::TracerProvider>, TraceError> {
+    let Some(endpoint) = config.endpoint.as_deref().filter(|_| config.traces) else {
+        return Ok(None);
+    };
+    let exporter = build_exporter!(
+        SpanExporter::builder(),
+        config,
+        endpoint,
+        "/v1/traces",
+        TraceError::from
+    )?;
+    let sampler = Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(config.sampling_ratio)));
+    Ok(Some(
+        trace::TracerProvider::builder()
+            .with_resource(resource(config))
+            .with_sampler(sampler)
+            // .with_simple_exporter(exporter)
+            .with_batch_exporter(exporter, opentelemetry_sdk::runtime::TokioCurrentThread)
+            .build(),
+    ))
+}
+pub fn init_metrics(config: &TelemetryConfig) -> Result<Option<PeriodicReader>, MetricError> {
+    let Some(endpoint) = config.endpoint.as_deref().filter(|_| config.metrics) else {
+        return Ok(None);
+    };
+    let exporter = build_exporter!(
+        MetricExporter::builder(),
+        config,
+        endpoint,
+        "/v1/metrics",
+        MetricError::Config
+    )?;
+    Ok(Some(
+        PeriodicReader::builder(exporter, opentelemetry_sdk::runtime::TokioCurrentThread)
+            .with_interval(Duration::from_millis(config.metrics_interval_ms))
+            .with_timeout(Duration::from_secs(10))
+            .build(),
+    ))
+}
+pub fn init_logs(config: &TelemetryConfig) -> Result<Option<LoggerProvider>, LogError> {
+    let Some(endpoint) = config.endpoint.as_deref().filter(|_| config.logs) else {
+        return Ok(None);
+    };
+    let exporter = build_exporter!(
+        LogExporter::builder(),
+        config,
+        endpoint,
+        "/v1/logs",
+        LogError::from
+    )?;
+
+    Ok(Some(
+        LoggerProvider::builder()
+            .with_resource(resource(config))
+            .with_batch_exporter(exporter, opentelemetry_sdk::runtime::TokioCurrentThread)
+            .build(),
+    ))
 }
@@ -0,0 +1,185 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use actix_web::rt::{task::spawn_blocking, time::interval};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error};
+
+use crate::{
+    app_config::TagConfig,
+    device::Device,
+    history::{History, Point},
+    rate_limiter::Priority,
+};
+
+/// 一次读保持寄存器最多125个
+const MAX_BLOCK: u16 = 125;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Quality {
+    Good,
+    /// 最近一次轮询失败，值是之前读到的
+    Bad,
+}
+
+impl Quality {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Quality::Good => "good",
+            Quality::Bad => "bad",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Sample {
+    pub value: u16,
+    pub quality: Quality,
+    pub timestamp: DateTime<Local>,
+}
+
+/// 数据点当前的值，没有读到过时value为None
+#[derive(Serialize, Clone, Debug)]
+pub struct TagValue {
+    pub name: String,
+    pub register: u16,
+    pub value: Option<u16>,
+    pub unit: Option<String>,
+    pub quality: Quality,
+    pub timestamp: Option<DateTime<Local>>,
+}
+
+/// 轮询到的寄存器值，按地址保存
+#[derive(Default)]
+pub struct PollCache {
+    registers: Mutex<HashMap<u16, Sample>>,
+}
+
+impl PollCache {
+    pub fn get(&self, address: u16) -> Option<Sample> {
+        self.registers.lock().unwrap().get(&address).copied()
+    }
+
+    pub fn update(&self, start: u16, values: &[u16], timestamp: DateTime<Local>) {
+        let mut registers = self.registers.lock().unwrap();
+        for (address, value) in (start..=u16::MAX).zip(values.iter().copied()) {
+            registers.insert(
+                address,
+                Sample {
+                    value,
+                    quality: Quality::Good,
+                    timestamp,
+                },
+            );
+        }
+    }
+
+    /// 读失败时保留旧值，只把质量标成bad
+    pub fn mark_bad(&self, start: u16, count: u16) {
+        let mut registers = self.registers.lock().unwrap();
+        for address in (start..=u16::MAX).take(count as usize) {
+            if let Some(sample) = registers.get_mut(&address) {
+                sample.quality = Quality::Bad;
+            }
+        }
+    }
+}
+
+pub fn tag_value(device: &Device, tag: &TagConfig) -> TagValue {
+    let sample = device.cache.get(tag.register);
+    TagValue {
+        name: tag.name.clone(),
+        register: tag.register,
+        value: sample.map(|sample| sample.value),
+        unit: tag.unit.clone(),
+        quality: sample.map_or(Quality::Bad, |sample| sample.quality),
+        timestamp: sample.map(|sample| sample.timestamp),
+    }
+}
+
+/// 把数据点的寄存器合并成几次连续的读，返回(起始地址, 数量)
+pub fn blocks(tags: &[TagConfig], max_gap: u16) -> Vec<(u16, u16)> {
+    let mut registers: Vec<u16> = tags.iter().map(|tag| tag.register).collect();
+    registers.sort_unstable();
+    registers.dedup();
+    let mut blocks: Vec<(u16, u16)> = Vec::new();
+    for register in registers {
+        match blocks.last_mut() {
+            Some((start, count))
+                if (register - (*start + *count - 1)) as u32 <= max_gap as u32 + 1
+                    && register - *start < MAX_BLOCK =>
+            {
+                *count = register - *start + 1;
+            }
+            _ => blocks.push((register, 1)),
+        }
+    }
+    blocks
+}
+
+/// 按配置的周期轮询设备的数据点，更新缓存并存历史，直到进程退出
+pub async fn poll_loop(device: Arc<Device>, history: Arc<History>) {
+    let blocks = blocks(&device.tags, device.poll.max_gap);
+    let mut ticker = interval(Duration::from_millis(device.poll.interval_ms.max(100)));
+    loop {
+        ticker.tick().await;
+        for &(start, count) in &blocks {
+            poll_block(&device, &history, start, count).await;
+        }
+    }
+}
+
+async fn poll_block(device: &Device, history: &Arc<History>, start: u16, count: u16) {
+    match device
+        .read_holding_registers(Priority::Background, start, count)
+        .await
+    {
+        Ok(values) => device.cache.update(start, &values, Local::now()),
+        Err(err) => {
+            debug!(
+                "轮询设备{}地址{}-{}失败：{}",
+                device.name,
+                start,
+                start as u32 + count as u32 - 1,
+                err
+            );
+            device.cache.mark_bad(start, count);
+        }
+    }
+    let end = start as u32 + count as u32;
+    let points: Vec<(TagConfig, Point)> = device
+        .tags
+        .iter()
+        .filter(|tag| tag.history && (start as u32..end).contains(&(tag.register as u32)))
+        .map(|tag| {
+            let value = tag_value(device, tag);
+            let point = Point {
+                t: Local::now(),
+                // 读失败时不存旧值，免得降采样时被当成真实的值
+                v: value.value.filter(|_| value.quality == Quality::Good),
+                q: value.quality,
+            };
+            (tag.clone(), point)
+        })
+        .collect();
+    if points.is_empty() {
+        return;
+    }
+    // 写历史文件是阻塞的文件操作，放到阻塞线程池里，不占用轮询所在的异步线程
+    let history = history.clone();
+    let name = device.name.clone();
+    if let Err(err) = spawn_blocking(move || {
+        for (tag, point) in points {
+            history.record(&name, &tag, point);
+        }
+    })
+    .await
+    {
+        error!("写入设备{}的历史失败：{}", device.name, err);
+    }
+}
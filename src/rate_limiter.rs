@@ -0,0 +1,366 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use actix_web::rt::time::{sleep, timeout};
+use serde::Serialize;
+use tokio::sync::Notify;
+
+use crate::app_config::RateLimitConfig;
+
+/// 请求的优先级，排队时高优先级的先发
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    /// 写寄存器、路线命令
+    Control,
+    /// HTTP接口上的读
+    Interactive,
+    /// 探测、后台轮询
+    Background,
+}
+
+impl Priority {
+    const ALL: [Priority; 3] = [
+        Priority::Control,
+        Priority::Interactive,
+        Priority::Background,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Priority::Control => "control",
+            Priority::Interactive => "interactive",
+            Priority::Background => "background",
+        }
+    }
+
+    fn lane(&self) -> usize {
+        *self as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum RateLimited {
+    QueueFull,
+    /// 排队超过了`queue_timeout_ms`
+    Deadline,
+}
+
+impl fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RateLimited::QueueFull => write!(f, "请求队列已满"),
+            RateLimited::Deadline => write!(f, "排队超时"),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct QueueSnapshot {
+    pub depth: usize,
+    pub max_depth: usize,
+    pub control: usize,
+    pub interactive: usize,
+    pub background: usize,
+}
+
+struct Waiter {
+    ticket: u64,
+    since: Instant,
+    /// 轮到时只唤醒这一个等待方
+    notify: Arc<Notify>,
+}
+
+struct Inner {
+    /// 有请求正在发送（或者已经轮到它但还在等帧间隔）
+    busy: bool,
+    next_ticket: u64,
+    /// 已经轮到但还没被等待方取走的票
+    granted: Option<u64>,
+    lanes: [VecDeque<Waiter>; 3],
+    last_start: Option<Instant>,
+    last_end: Option<Instant>,
+}
+
+impl Inner {
+    fn depth(&self) -> usize {
+        self.lanes.iter().map(VecDeque::len).sum()
+    }
+}
+
+/// 持有期间其他请求排队，释放时记录请求结束时间并把机会交给下一个
+pub struct LimiterPermit<'a> {
+    limiter: &'a RateLimiter,
+    /// 请求已经开始；等帧间隔时超时或被取消的不算发过请求，不记录结束时间
+    started: bool,
+}
+
+impl Drop for LimiterPermit<'_> {
+    fn drop(&mut self) {
+        let mut inner = self.limiter.inner.lock().unwrap();
+        if self.started {
+            inner.last_end = Some(Instant::now());
+        }
+        self.limiter.grant_next(&mut inner);
+    }
+}
+
+/// 排队中的请求，被取消时从队列里删掉；如果已经轮到了，把机会交给下一个
+struct Waiting<'a> {
+    limiter: &'a RateLimiter,
+    ticket: u64,
+    done: bool,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        let mut inner = self.limiter.inner.lock().unwrap();
+        for lane in inner.lanes.iter_mut() {
+            lane.retain(|waiter| waiter.ticket != self.ticket);
+        }
+        if inner.granted == Some(self.ticket) {
+            inner.granted = None;
+            self.limiter.grant_next(&mut inner);
+        }
+    }
+}
+
+/// 每个设备一个，保证请求串行发送、满足限速和最小帧间隔，并按优先级排队
+pub struct RateLimiter {
+    /// 两个请求开始之间的最小间隔，由max_rps换算
+    interval: Duration,
+    min_gap: Duration,
+    max_queue: usize,
+    queue_timeout: Duration,
+    starvation: Duration,
+    inner: Mutex<Inner>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        RateLimiter {
+            interval: config
+                .max_rps
+                .filter(|rps| *rps > 0.0)
+                .map_or(Duration::ZERO, |rps| Duration::from_secs_f64(1.0 / rps)),
+            min_gap: Duration::from_millis(config.min_gap_ms),
+            max_queue: config.max_queue,
+            queue_timeout: Duration::from_millis(config.queue_timeout_ms),
+            starvation: Duration::from_millis(config.starvation_ms),
+            inner: Mutex::new(Inner {
+                busy: false,
+                next_ticket: 0,
+                granted: None,
+                lanes: Default::default(),
+                last_start: None,
+                last_end: None,
+            }),
+        }
+    }
+
+    pub fn snapshot(&self) -> QueueSnapshot {
+        let inner = self.inner.lock().unwrap();
+        QueueSnapshot {
+            depth: inner.depth(),
+            max_depth: self.max_queue,
+            control: inner.lanes[Priority::Control.lane()].len(),
+            interactive: inner.lanes[Priority::Interactive.lane()].len(),
+            background: inner.lanes[Priority::Background.lane()].len(),
+        }
+    }
+
+    /// 选下一个请求：排队超过`starvation`的按先来后到，否则按优先级
+    fn grant_next(&self, inner: &mut Inner) {
+        let now = Instant::now();
+        let starving = Priority::ALL
+            .iter()
+            .filter_map(|priority| {
+                inner.lanes[priority.lane()]
+                    .front()
+                    .filter(|waiter| now.duration_since(waiter.since) >= self.starvation)
+                    .map(|waiter| (waiter.since, priority.lane()))
+            })
+            .min()
+            .map(|(_, lane)| lane);
+        let lane = starving.or_else(|| inner.lanes.iter().position(|lane| !lane.is_empty()));
+        match lane.and_then(|lane| inner.lanes[lane].pop_front()) {
+            Some(waiter) => {
+                inner.granted = Some(waiter.ticket);
+                waiter.notify.notify_one();
+            }
+            None => inner.busy = false,
+        }
+    }
+
+    /// 同一个许可里连续发请求时，上一个请求之后最早什么时候可以发下一个
+    pub fn next_ready(&self, last_start: Instant, last_end: Instant) -> Instant {
+        (last_start + self.interval).max(last_end + self.min_gap)
+    }
+
+    /// 等到可以发下一个请求
+    pub async fn acquire(&self, priority: Priority) -> Result<LimiterPermit<'_>, RateLimited> {
+        let deadline = Instant::now() + self.queue_timeout;
+        let notify = Arc::new(Notify::new());
+        let ticket = {
+            let mut inner = self.inner.lock().unwrap();
+            if !inner.busy && inner.depth() == 0 {
+                inner.busy = true;
+                None
+            } else if inner.depth() >= self.max_queue {
+                return Err(RateLimited::QueueFull);
+            } else {
+                let ticket = inner.next_ticket;
+                inner.next_ticket += 1;
+                inner.lanes[priority.lane()].push_back(Waiter {
+                    ticket,
+                    since: Instant::now(),
+                    notify: notify.clone(),
+                });
+                Some(ticket)
+            }
+        };
+        if let Some(ticket) = ticket {
+            let mut waiting = Waiting {
+                limiter: self,
+                ticket,
+                done: false,
+            };
+            loop {
+                // notify_one在没人等待时会保留通知，检查之后、等待之前的通知不会丢
+                {
+                    let mut inner = self.inner.lock().unwrap();
+                    if inner.granted == Some(ticket) {
+                        inner.granted = None;
+                        waiting.done = true;
+                        break;
+                    }
+                }
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if timeout(remaining, notify.notified()).await.is_err() {
+                    return Err(RateLimited::Deadline);
+                }
+            }
+        }
+        let mut permit = LimiterPermit {
+            limiter: self,
+            started: false,
+        };
+        let ready_at = {
+            let inner = self.inner.lock().unwrap();
+            [
+                inner.last_start.map(|start| start + self.interval),
+                inner.last_end.map(|end| end + self.min_gap),
+            ]
+            .into_iter()
+            .flatten()
+            .max()
+        };
+        if let Some(ready_at) = ready_at {
+            if ready_at > deadline {
+                // 释放许可交给下一个，没有发请求所以不更新last_end
+                return Err(RateLimited::Deadline);
+            }
+            sleep(ready_at.saturating_duration_since(Instant::now())).await;
+        }
+        self.inner.lock().unwrap().last_start = Some(Instant::now());
+        permit.started = true;
+        Ok(permit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::rt::spawn;
+
+    use super::*;
+
+    fn limiter(min_gap_ms: u64, starvation_ms: u64) -> Arc<RateLimiter> {
+        Arc::new(RateLimiter::new(&RateLimitConfig {
+            max_rps: None,
+            min_gap_ms,
+            max_queue: 64,
+            queue_timeout_ms: 5000,
+            starvation_ms,
+        }))
+    }
+
+    /// 不停地排队发请求，每个请求占用设备`busy`
+    fn flood(limiter: &Arc<RateLimiter>, priority: Priority, tasks: usize, busy: Duration) {
+        for _ in 0..tasks {
+            let limiter = limiter.clone();
+            spawn(async move {
+                loop {
+                    if let Ok(_permit) = limiter.acquire(priority).await {
+                        sleep(busy).await;
+                    }
+                }
+            });
+        }
+    }
+
+    #[actix_web::test]
+    async fn control_is_not_delayed_by_background_flood() {
+        let limiter = limiter(5, 1000);
+        flood(
+            &limiter,
+            Priority::Background,
+            16,
+            Duration::from_millis(10),
+        );
+        sleep(Duration::from_millis(100)).await;
+        for _ in 0..5 {
+            let started = Instant::now();
+            let permit = limiter.acquire(Priority::Control).await.unwrap();
+            // 最多等正在发的一个请求加一个帧间隔
+            assert!(
+                started.elapsed() < Duration::from_millis(50),
+                "控制请求等了{:?}",
+                started.elapsed()
+            );
+            drop(permit);
+            sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    #[actix_web::test]
+    async fn background_is_not_starved_by_control_flood() {
+        let limiter = limiter(0, 100);
+        flood(&limiter, Priority::Control, 8, Duration::from_millis(5));
+        sleep(Duration::from_millis(50)).await;
+        let started = Instant::now();
+        let permit = limiter.acquire(Priority::Background).await;
+        assert!(permit.is_ok());
+        assert!(
+            started.elapsed() < Duration::from_millis(300),
+            "后台请求等了{:?}",
+            started.elapsed()
+        );
+    }
+
+    #[actix_web::test]
+    async fn deadline_while_waiting_for_gap_releases_the_turn() {
+        let limiter = Arc::new(RateLimiter::new(&RateLimitConfig {
+            max_rps: None,
+            min_gap_ms: 200,
+            max_queue: 64,
+            queue_timeout_ms: 50,
+            starvation_ms: 1000,
+        }));
+        drop(limiter.acquire(Priority::Control).await.unwrap());
+        let last_end = limiter.inner.lock().unwrap().last_end;
+        assert!(matches!(
+            limiter.acquire(Priority::Control).await,
+            Err(RateLimited::Deadline)
+        ));
+        let inner = limiter.inner.lock().unwrap();
+        assert!(!inner.busy);
+        assert_eq!(inner.last_end, last_end);
+    }
+}
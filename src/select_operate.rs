@@ -0,0 +1,223 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Local};
+use rand::RngCore;
+use serde::Serialize;
+
+use crate::app_config::{RouteCommand, SelectBeforeOperateConfig};
+
+/// 选择成功后返回给调用方
+#[derive(Serialize, Clone, Debug)]
+pub struct Selection {
+    pub token: String,
+    pub from: String,
+    pub to: String,
+    pub expires_at: DateTime<Local>,
+}
+
+struct Selected {
+    from: String,
+    to: String,
+    /// 路线写的设备和寄存器，同一个寄存器同时只能有一个有效的选择
+    device: String,
+    register: u16,
+    identity: String,
+    expires: Instant,
+    /// 执行过的令牌保留到过期，这样重复执行时能告诉调用方"已经用过"而不是"不存在"
+    used: bool,
+}
+
+#[derive(Debug)]
+pub enum SelectError {
+    /// 同一个设备寄存器已经被别人选择，还没执行也没过期
+    Busy {
+        from: String,
+        to: String,
+        identity: String,
+    },
+}
+
+impl fmt::Display for SelectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SelectError::Busy { from, to, identity } => write!(
+                f,
+                "{}已经选择了路线{}->{}，写的是同一个寄存器，请等它执行或过期",
+                identity, from, to
+            ),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum OperateError {
+    UnknownToken,
+    Expired,
+    AlreadyUsed,
+    /// 令牌不是这个调用方选择的
+    WrongIdentity,
+}
+
+impl fmt::Display for OperateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OperateError::UnknownToken => write!(f, "令牌不存在"),
+            OperateError::Expired => write!(f, "令牌已过期，请重新选择"),
+            OperateError::AlreadyUsed => write!(f, "令牌已经执行过"),
+            OperateError::WrongIdentity => write!(f, "令牌不是当前用户选择的"),
+        }
+    }
+}
+
+/// 路线命令的选择-执行：选择时保留命令并发放一次性令牌，执行时消费令牌
+pub struct SelectBeforeOperate {
+    pub required: bool,
+    ttl: Duration,
+    selections: Mutex<HashMap<String, Selected>>,
+}
+
+impl SelectBeforeOperate {
+    pub fn new(config: &SelectBeforeOperateConfig) -> Self {
+        SelectBeforeOperate {
+            required: config.required,
+            ttl: Duration::from_millis(config.ttl_ms),
+            selections: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// 过期或执行过的令牌再保留一个ttl，这期间执行还能得到"已过期"、"已经执行过"
+    fn purge(&self, selections: &mut HashMap<String, Selected>, now: Instant) {
+        selections.retain(|_, selected| selected.expires + self.ttl > now);
+    }
+
+    /// 选择路线。同一个设备寄存器上别人的有效选择会让这次选择失败，自己之前的选择被这次替换
+    pub fn select(&self, route: &RouteCommand, identity: &str) -> Result<Selection, SelectError> {
+        let now = Instant::now();
+        let mut selections = self.selections.lock().unwrap();
+        self.purge(&mut selections, now);
+        let conflicts = |selected: &Selected| {
+            !selected.used
+                && selected.expires > now
+                && selected.device == route.device
+                && selected.register == route.register
+        };
+        if let Some(selected) = selections
+            .values()
+            .find(|selected| conflicts(selected) && selected.identity != identity)
+        {
+            return Err(SelectError::Busy {
+                from: selected.from.clone(),
+                to: selected.to.clone(),
+                identity: selected.identity.clone(),
+            });
+        }
+        selections.retain(|_, selected| !conflicts(selected));
+        let mut bytes = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let token: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+        selections.insert(
+            token.clone(),
+            Selected {
+                from: route.from.clone(),
+                to: route.to.clone(),
+                device: route.device.clone(),
+                register: route.register,
+                identity: identity.to_string(),
+                expires: now + self.ttl,
+                used: false,
+            },
+        );
+        Ok(Selection {
+            token,
+            from: route.from.clone(),
+            to: route.to.clone(),
+            expires_at: Local::now() + self.ttl,
+        })
+    }
+
+    /// 消费令牌，返回选择时的(起点, 终点)。同一个令牌只有第一次调用会成功
+    pub fn operate(&self, token: &str, identity: &str) -> Result<(String, String), OperateError> {
+        let now = Instant::now();
+        let mut selections = self.selections.lock().unwrap();
+        // 先查令牌再清理，过期的令牌返回Expired而不是UnknownToken
+        let result = match selections.get_mut(token) {
+            None => Err(OperateError::UnknownToken),
+            Some(selected) if selected.used => Err(OperateError::AlreadyUsed),
+            Some(selected) if selected.expires <= now => Err(OperateError::Expired),
+            Some(selected) if selected.identity != identity => Err(OperateError::WrongIdentity),
+            Some(selected) => {
+                selected.used = true;
+                Ok((selected.from.clone(), selected.to.clone()))
+            }
+        };
+        self.purge(&mut selections, now);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use super::*;
+
+    fn route(from: &str, to: &str, register: u16) -> RouteCommand {
+        RouteCommand {
+            from: from.to_string(),
+            to: to.to_string(),
+            device: "main".to_string(),
+            register,
+            value: 1,
+            description: String::new(),
+        }
+    }
+
+    fn sbo(ttl_ms: u64) -> SelectBeforeOperate {
+        SelectBeforeOperate::new(&SelectBeforeOperateConfig {
+            required: true,
+            ttl_ms,
+        })
+    }
+
+    #[test]
+    fn same_register_can_only_be_selected_once() {
+        let sbo = sbo(10000);
+        let first = sbo.select(&route("A", "B", 10), "alice").unwrap();
+        assert!(matches!(
+            sbo.select(&route("A", "C", 10), "bob"),
+            Err(SelectError::Busy { .. })
+        ));
+        // 别的寄存器不受影响
+        sbo.select(&route("A", "C", 11), "bob").unwrap();
+        // 自己重新选择替换掉之前的令牌
+        let second = sbo.select(&route("A", "C", 10), "alice").unwrap();
+        assert!(matches!(
+            sbo.operate(&first.token, "alice"),
+            Err(OperateError::UnknownToken)
+        ));
+        assert_eq!(
+            sbo.operate(&second.token, "alice").unwrap(),
+            ("A".to_string(), "C".to_string())
+        );
+        // 执行之后别人可以选择
+        sbo.select(&route("A", "B", 10), "bob").unwrap();
+    }
+
+    #[test]
+    fn expired_token_is_reported_as_expired() {
+        let sbo = sbo(50);
+        let selection = sbo.select(&route("A", "B", 10), "alice").unwrap();
+        sleep(Duration::from_millis(60));
+        // 选择时的清理不能让过期的令牌变成不存在
+        sbo.select(&route("A", "C", 11), "bob").unwrap();
+        assert!(matches!(
+            sbo.operate(&selection.token, "alice"),
+            Err(OperateError::Expired)
+        ));
+    }
+}
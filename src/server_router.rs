@@ -1,17 +1,31 @@
-use std::{collections::HashMap, time::Duration};
-
-use crate::{modbus_manager::ModbusManager, Pool};
-use actix_web::{get, rt::time::timeout, web, Error, Responder};
+use crate::alarms::{AckError, AlarmEngine, AlarmEvent, AlarmStatus};
+use crate::app_config::Role;
+use crate::audit::AuditLog;
+use crate::auth::Identity;
+use crate::circuit_breaker::BreakerSnapshot;
+use crate::commands::{write_registers, Origin, RouteTable, WriteError};
+use crate::device::{DeviceError, Devices};
+use crate::device_health::HealthSnapshot;
+use crate::events::{sse_stream, EventBus};
+use crate::export::{history_stream, snapshot_stream, ExportFormat};
+use crate::history::{downsample, Bucket, History, Point};
+use crate::idempotency::idempotency_middleware;
+use crate::logging::LogLevel;
+use crate::metrics::PrometheusReader;
+use crate::rate_limiter::{Priority, QueueSnapshot};
+use crate::select_operate::SelectBeforeOperate;
+use actix_web::middleware::from_fn;
+use actix_web::{
+    delete, get, http::header, post, put, web, Error, HttpRequest, HttpResponse, Responder,
+};
 // use backoff::ExponentialBackoff;
 // use backoff::{retry, retry_notify};
 // use backon::ExponentialBuilder;
 // use backon::Retryable;
-use deadpool::managed::{Manager, Object};
-use serde::Serialize;
-use tokio::time::error::Elapsed;
-use tokio_modbus::client::Client;
-use tokio_modbus::{client::Reader, ExceptionCode};
-use tracing::{error, info};
+use chrono::{DateTime, Local, TimeDelta};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tracing::{error, info, warn};
 
 #[get("/hello/{name}")]
 async fn greet(name: web::Path<String>) -> impl Responder {
@@ -21,64 +35,371 @@ async fn greet(name: web::Path<String>) -> impl Responder {
 #[get("/modbus/{name}")]
 pub async fn get_modbus_value(
     name: web::Path<String>,
-    pools: web::Data<HashMap<String, Pool>>,
-) -> Result<impl Responder, Error> {
+    identity: Identity,
+    devices: web::Data<Devices>,
+) -> Result<HttpResponse, Error> {
     let name = name.as_str();
-    let modbus = pools.get(name);
-    match modbus {
-        Some(modbus_context) => {
-            let mut modbus: Object<ModbusManager> = modbus_context.get().await.unwrap();
-
-            let values = timeout(
-                Duration::from_secs(1),
-                modbus.context.read_holding_registers(0, 20),
-            )
-            .await;
-            // let values = read_data(modbus).await;
-            return match values {
-                Ok(Ok(Ok(values))) => Ok(web::Json(Response::success(values))),
-                Ok(Ok(Err(err))) => {
-                    error!("读取成功，但服务器返回错误：{:?}", err);
-                    Ok(web::Json(Response::error(err.to_string())))
-                }
-                Ok(Err(err)) => {
-                    //服务器主动关闭与客户端的连接会进入这个异常
-                    error!("读取失败：{:?}", err);
-                    modbus.status = false;
-                    // let _ = Object::take(modbus);
-
-                    Ok(web::Json(Response::error(err.to_string())))
-                }
-                Err(e) => {
-                    error!("超时读取失败：{:?}", e);
-                    modbus.status = false;
-                    // let _ = Object::take(modbus);
-                    Ok(web::Json(Response::error(e.to_string())))
-                }
-            };
-        }
-        None => Ok(web::Json(Response::error(format!(
+    identity.authorize(Role::Viewer, Some(name))?;
+    let device = devices.get(name);
+    match device {
+        Some(device) => match device
+            .read_holding_registers(Priority::Interactive, 0, 20)
+            .await
+        {
+            Ok(values) => Ok(HttpResponse::Ok().json(Response::success(values))),
+            Err(err) => {
+                error!("读取设备{}失败：{}", name, err);
+                Ok(device_error_response(&err))
+            }
+        },
+        None => Ok(HttpResponse::Ok().json(Response::error(format!(
             "不存在配置名为{}的modbus配置！",
             name,
         )))),
     }
 }
 
+#[derive(Deserialize)]
+pub struct WriteRequest {
+    values: Vec<u16>,
+}
+
+#[post(
+    "/modbus/{name}/registers/{address}",
+    wrap = "from_fn(idempotency_middleware)"
+)]
+pub async fn write_modbus_registers(
+    path: web::Path<(String, u16)>,
+    body: web::Json<WriteRequest>,
+    req: HttpRequest,
+    identity: Identity,
+    devices: web::Data<Devices>,
+    audit: web::Data<AuditLog>,
+) -> Result<HttpResponse, Error> {
+    let (name, address) = path.into_inner();
+    identity.authorize(Role::Operator, Some(&name))?;
+    let Some(device) = devices.get(&name) else {
+        return Ok(HttpResponse::NotFound().json(Response::error(format!(
+            "不存在配置名为{}的modbus配置！",
+            name,
+        ))));
+    };
+    let values = body.into_inner().values;
+    if values.is_empty() || values.len() > 123 {
+        return Ok(HttpResponse::BadRequest().json(Response::error("一次写入1到123个寄存器")));
+    }
+    match write_registers(device, address, values, &origin(&req, &identity), &audit).await {
+        Ok(()) => Ok(HttpResponse::Ok().json(Response::success(()))),
+        Err(err) => {
+            error!("写入设备{}地址{}失败：{}", name, address, err);
+            Ok(write_error_response(&err))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RouteRequest {
+    from: String,
+    to: String,
+}
+
+/// 流水线路线切换，原来salvo版本里CONTROL_MAP的功能
+#[post("/route", wrap = "from_fn(idempotency_middleware)")]
+pub async fn switch_route(
+    body: web::Json<RouteRequest>,
+    req: HttpRequest,
+    identity: Identity,
+    routes: web::Data<RouteTable>,
+    devices: web::Data<Devices>,
+    audit: web::Data<AuditLog>,
+    sbo: web::Data<SelectBeforeOperate>,
+) -> Result<HttpResponse, Error> {
+    identity.authorize(Role::Operator, None)?;
+    if sbo.required {
+        return Ok(HttpResponse::Conflict().json(Response::error(
+            "路线命令需要先POST /route/select，再用令牌POST /route/operate",
+        )));
+    }
+    let RouteRequest { from, to } = body.into_inner();
+    run_route(&from, &to, &req, &identity, &routes, &devices, &audit).await
+}
+
+/// 选择路线：检查路线和权限，返回一次性的执行令牌
+#[post("/route/select")]
+pub async fn select_route(
+    body: web::Json<RouteRequest>,
+    identity: Identity,
+    routes: web::Data<RouteTable>,
+    sbo: web::Data<SelectBeforeOperate>,
+) -> Result<HttpResponse, Error> {
+    identity.authorize(Role::Operator, None)?;
+    let RouteRequest { from, to } = body.into_inner();
+    let Some(route) = routes.get(&from, &to) else {
+        return Ok(
+            HttpResponse::NotFound().json(Response::error(format!("没有从{}到{}的路线", from, to)))
+        );
+    };
+    identity.authorize(Role::Operator, Some(&route.device))?;
+    let selection = match sbo.select(route, &identity.name) {
+        Ok(selection) => selection,
+        Err(err) => {
+            warn!("{}选择路线{}->{}失败：{}", identity.name, from, to, err);
+            return Ok(HttpResponse::Conflict().json(Response::error(err.to_string())));
+        }
+    };
+    info!("{}选择了路线{}->{}", identity.name, from, to);
+    Ok(HttpResponse::Ok().json(Response::success(selection)))
+}
+
+#[derive(Deserialize)]
+pub struct OperateRequest {
+    token: String,
+}
+
+/// 执行选择过的路线，令牌只能用一次
+#[post("/route/operate", wrap = "from_fn(idempotency_middleware)")]
+pub async fn operate_route(
+    body: web::Json<OperateRequest>,
+    req: HttpRequest,
+    identity: Identity,
+    routes: web::Data<RouteTable>,
+    devices: web::Data<Devices>,
+    audit: web::Data<AuditLog>,
+    sbo: web::Data<SelectBeforeOperate>,
+) -> Result<HttpResponse, Error> {
+    identity.authorize(Role::Operator, None)?;
+    let (from, to) = match sbo.operate(&body.token, &identity.name) {
+        Ok(selected) => selected,
+        Err(err) => {
+            warn!("{}执行路线令牌失败：{}", identity.name, err);
+            return Ok(HttpResponse::Conflict().json(Response::error(err.to_string())));
+        }
+    };
+    run_route(&from, &to, &req, &identity, &routes, &devices, &audit).await
+}
+
+async fn run_route(
+    from: &str,
+    to: &str,
+    req: &HttpRequest,
+    identity: &Identity,
+    routes: &RouteTable,
+    devices: &Devices,
+    audit: &AuditLog,
+) -> Result<HttpResponse, Error> {
+    let Some(route) = routes.get(from, to) else {
+        return Ok(
+            HttpResponse::NotFound().json(Response::error(format!("没有从{}到{}的路线", from, to)))
+        );
+    };
+    identity.authorize(Role::Operator, Some(&route.device))?;
+    let Some(device) = devices.get(&route.device) else {
+        return Ok(
+            HttpResponse::InternalServerError().json(Response::error(format!(
+                "路线{}->{}配置的设备{}不存在",
+                from, to, route.device
+            ))),
+        );
+    };
+    let mut origin = origin(req, identity);
+    origin.command = Some(format!("{}->{} {}", from, to, route.description));
+    match write_registers(device, route.register, vec![route.value], &origin, audit).await {
+        Ok(()) => {
+            info!("路线{}->{}切换成功：{}", from, to, route.description);
+            Ok(HttpResponse::Ok().json(Response::success(route.description.clone())))
+        }
+        Err(err) => {
+            error!("路线{}->{}切换失败：{}", from, to, err);
+            Ok(write_error_response(&err))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AuditQuery {
+    limit: Option<usize>,
+}
+
+#[get("/audit")]
+pub async fn get_audit(
+    query: web::Query<AuditQuery>,
+    identity: Identity,
+    audit: web::Data<AuditLog>,
+) -> Result<HttpResponse, Error> {
+    identity.authorize(Role::Admin, None)?;
+    Ok(HttpResponse::Ok().json(Response::success(audit.recent(query.limit.unwrap_or(100)))))
+}
+
+#[get("/admin/log-level")]
+pub async fn get_log_level(
+    identity: Identity,
+    log_level: web::Data<LogLevel>,
+) -> Result<HttpResponse, Error> {
+    identity.authorize(Role::Admin, None)?;
+    Ok(HttpResponse::Ok().json(Response::success(log_level.snapshot())))
+}
+
+#[derive(Deserialize)]
+pub struct LogLevelRequest {
+    /// EnvFilter指令，可以按target设置，比如"info,modbus::device=trace"
+    directives: String,
+    /// 多少秒后恢复成启动时的配置，不传时一直生效
+    duration_secs: Option<u64>,
+}
+
+#[put("/admin/log-level")]
+pub async fn set_log_level(
+    body: web::Json<LogLevelRequest>,
+    identity: Identity,
+    log_level: web::Data<LogLevel>,
+) -> Result<HttpResponse, Error> {
+    identity.authorize(Role::Admin, None)?;
+    let duration = body.duration_secs.map(Duration::from_secs);
+    match log_level.into_inner().set(&body.directives, duration) {
+        Ok(snapshot) => Ok(HttpResponse::Ok().json(Response::success(snapshot))),
+        Err(err) => Ok(HttpResponse::BadRequest().json(Response::error(err))),
+    }
+}
+
+#[delete("/admin/log-level")]
+pub async fn reset_log_level(
+    identity: Identity,
+    log_level: web::Data<LogLevel>,
+) -> Result<HttpResponse, Error> {
+    identity.authorize(Role::Admin, None)?;
+    Ok(HttpResponse::Ok().json(Response::success(log_level.reset())))
+}
+
+fn origin(req: &HttpRequest, identity: &Identity) -> Origin {
+    Origin {
+        client_ip: req
+            .connection_info()
+            .realip_remote_addr()
+            .map(str::to_string),
+        identity: Some(identity.name.clone()),
+        ..Default::default()
+    }
+}
+
+fn write_error_response(err: &WriteError) -> HttpResponse {
+    match err {
+        WriteError::Policy(violation) => {
+            HttpResponse::Forbidden().json(Response::rejected(err.to_string(), violation))
+        }
+        WriteError::Device(err) => device_error_response(err),
+    }
+}
+
+fn device_error_response(err: &DeviceError) -> HttpResponse {
+    match err {
+        DeviceError::Unavailable { retry_after, .. } => HttpResponse::ServiceUnavailable()
+            .insert_header((
+                header::RETRY_AFTER,
+                retry_after.as_secs_f64().ceil().to_string(),
+            ))
+            .json(Response::error(err.to_string())),
+        DeviceError::RateLimited { .. } => HttpResponse::TooManyRequests()
+            .insert_header((header::RETRY_AFTER, "1"))
+            .json(Response::error(err.to_string())),
+        _ => HttpResponse::Ok().json(Response::error(err.to_string())),
+    }
+}
+
+#[get("/devices")]
+pub async fn get_devices(
+    identity: Identity,
+    devices: web::Data<Devices>,
+) -> Result<impl Responder, Error> {
+    identity.authorize(Role::Viewer, None)?;
+    let mut statuses: Vec<DeviceStatus> = devices
+        .values()
+        .filter(|device| identity.can_access(&device.name))
+        .map(|device| {
+            let status = device.pool.status();
+            DeviceStatus {
+                name: device.name.clone(),
+                address: device.addr.clone(),
+                slave_id: device.slave,
+                tls_role: device.tls_role.clone(),
+                health: device.health.snapshot(),
+                breaker: device.breaker.snapshot(),
+                queue: device.limiter.snapshot(),
+                pool: PoolStatus {
+                    max_size: status.max_size,
+                    size: status.size,
+                    available: status.available,
+                    waiting: status.waiting,
+                },
+            }
+        })
+        .collect();
+    statuses.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(web::Json(Response::success(statuses)))
+}
+
+#[get("/metrics")]
+pub async fn get_metrics(
+    identity: Identity,
+    reader: web::Data<PrometheusReader>,
+) -> Result<HttpResponse, Error> {
+    identity.authorize(Role::Viewer, None)?;
+    match reader.render() {
+        Ok(text) => Ok(HttpResponse::Ok()
+            .content_type("text/plain; version=0.0.4; charset=utf-8")
+            .body(text)),
+        Err(err) => {
+            error!("采集指标失败：{:?}", err);
+            Ok(HttpResponse::InternalServerError().body(err.to_string()))
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct DeviceStatus {
+    name: String,
+    address: String,
+    slave_id: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tls_role: Option<String>,
+    #[serde(flatten)]
+    health: HealthSnapshot,
+    breaker: BreakerSnapshot,
+    queue: QueueSnapshot,
+    pool: PoolStatus,
+}
+#[derive(Serialize)]
+struct PoolStatus {
+    max_size: usize,
+    size: usize,
+    available: usize,
+    waiting: usize,
+}
+
 #[derive(Serialize)]
-struct Response<T> {
+pub(crate) struct Response<T> {
     success: bool,
     error: String,
     value: Option<T>,
 }
 impl<T> Response<T> {
-    fn success(value: T) -> Self {
+    pub(crate) fn success(value: T) -> Self {
         Response {
             success: true,
             error: String::new(),
             value: Some(value),
         }
     }
-    fn error(error: impl AsRef<str>) -> Self {
+    /// 失败但带上结构化的原因，比如写入策略拒绝的规则
+    pub(crate) fn rejected(error: impl AsRef<str>, value: T) -> Self {
+        Response {
+            success: false,
+            error: error.as_ref().into(),
+            value: Some(value),
+        }
+    }
+}
+impl Response<()> {
+    pub(crate) fn error(error: impl AsRef<str>) -> Self {
         Response {
             success: false,
             error: error.as_ref().into(),
@@ -86,3 +407,258 @@ impl<T> Response<T> {
         }
     }
 }
+
+#[derive(Deserialize)]
+pub struct HistoryQuery {
+    /// RFC 3339时间，不传时为`to`之前一小时
+    from: Option<DateTime<Local>>,
+    /// 不传时为现在
+    to: Option<DateTime<Local>>,
+    /// 降采样的时间段长度（秒），不传时返回原始点
+    step: Option<u64>,
+}
+
+/// 降采样最多返回多少个时间段
+const MAX_BUCKETS: i64 = 10_000;
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum HistoryPoints {
+    Raw(Vec<Point>),
+    Downsampled(Vec<Bucket>),
+}
+
+#[derive(Serialize)]
+struct HistorySeries {
+    device: String,
+    tag: String,
+    unit: Option<String>,
+    from: DateTime<Local>,
+    to: DateTime<Local>,
+    step: Option<u64>,
+    /// `from`时刻的值，即`from`之前最后存的点
+    previous: Option<Point>,
+    points: HistoryPoints,
+}
+
+#[get("/history/{device}/{tag}")]
+pub async fn get_history(
+    path: web::Path<(String, String)>,
+    query: web::Query<HistoryQuery>,
+    identity: Identity,
+    devices: web::Data<Devices>,
+    history: web::Data<History>,
+) -> Result<HttpResponse, Error> {
+    let (name, tag_name) = path.into_inner();
+    identity.authorize(Role::Viewer, Some(&name))?;
+    let Some(tag) = devices
+        .get(&name)
+        .and_then(|device| device.tags.iter().find(|tag| tag.name == tag_name))
+    else {
+        return Ok(HttpResponse::NotFound().json(Response::error(format!(
+            "设备{}没有数据点{}",
+            name, tag_name
+        ))));
+    };
+    let to = query.to.unwrap_or_else(Local::now);
+    let from = query.from.unwrap_or(to - TimeDelta::hours(1));
+    if from >= to {
+        return Ok(HttpResponse::BadRequest().json(Response::error("from必须早于to")));
+    }
+    let step = match query.step {
+        Some(0) => {
+            return Ok(HttpResponse::BadRequest().json(Response::error("step必须大于0")));
+        }
+        Some(step) if (to - from).num_seconds() / step as i64 > MAX_BUCKETS => {
+            return Ok(HttpResponse::BadRequest().json(Response::error(format!(
+                "时间段太多，最多{}个，请加大step",
+                MAX_BUCKETS
+            ))));
+        }
+        step => step,
+    };
+    let unit = tag.unit.clone();
+    let series = web::block(move || {
+        let previous = history.previous(&name, &tag_name, from);
+        let points = history.points(&name, &tag_name, from, to);
+        let points = match step {
+            Some(step) => HistoryPoints::Downsampled(downsample(
+                points,
+                from,
+                to,
+                TimeDelta::seconds(step as i64),
+            )),
+            None => HistoryPoints::Raw(points.collect()),
+        };
+        HistorySeries {
+            device: name,
+            tag: tag_name,
+            unit,
+            from,
+            to,
+            step,
+            previous,
+            points,
+        }
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(Response::success(series)))
+}
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    #[serde(default)]
+    format: ExportFormat,
+    from: Option<DateTime<Local>>,
+    to: Option<DateTime<Local>>,
+}
+
+fn attachment(format: ExportFormat, name: &str) -> (header::HeaderName, String) {
+    (
+        header::CONTENT_DISPOSITION,
+        format!(
+            "attachment; filename=\"{}-{}.{}\"",
+            name,
+            Local::now().format("%Y%m%dT%H%M%S"),
+            format.extension()
+        ),
+    )
+}
+
+/// 实时读一次设备所有数据点，导出CSV或JSON lines
+#[get("/export/{device}/snapshot")]
+pub async fn export_snapshot(
+    name: web::Path<String>,
+    query: web::Query<ExportQuery>,
+    identity: Identity,
+    devices: web::Data<Devices>,
+) -> Result<HttpResponse, Error> {
+    let name = name.into_inner();
+    identity.authorize(Role::Viewer, Some(&name))?;
+    let Some(device) = devices.get(&name) else {
+        return Ok(HttpResponse::NotFound().json(Response::error(format!(
+            "不存在配置名为{}的modbus配置！",
+            name,
+        ))));
+    };
+    Ok(HttpResponse::Ok()
+        .content_type(query.format.content_type())
+        .insert_header(attachment(query.format, &format!("{}-snapshot", name)))
+        .streaming(snapshot_stream(device.clone(), query.format)))
+}
+
+/// 导出一个数据点的历史，默认最近一天
+#[get("/export/{device}/{tag}/history")]
+pub async fn export_history(
+    path: web::Path<(String, String)>,
+    query: web::Query<ExportQuery>,
+    identity: Identity,
+    devices: web::Data<Devices>,
+    history: web::Data<History>,
+) -> Result<HttpResponse, Error> {
+    let (name, tag_name) = path.into_inner();
+    identity.authorize(Role::Viewer, Some(&name))?;
+    let Some(tag) = devices
+        .get(&name)
+        .and_then(|device| device.tags.iter().find(|tag| tag.name == tag_name))
+    else {
+        return Ok(HttpResponse::NotFound().json(Response::error(format!(
+            "设备{}没有数据点{}",
+            name, tag_name
+        ))));
+    };
+    let to = query.to.unwrap_or_else(Local::now);
+    let from = query.from.unwrap_or(to - TimeDelta::days(1));
+    if from >= to {
+        return Ok(HttpResponse::BadRequest().json(Response::error("from必须早于to")));
+    }
+    Ok(HttpResponse::Ok()
+        .content_type(query.format.content_type())
+        .insert_header(attachment(
+            query.format,
+            &format!("{}-{}-history", name, tag_name),
+        ))
+        .streaming(history_stream(
+            history.into_inner(),
+            name,
+            tag.clone(),
+            from,
+            to,
+            query.format,
+        )))
+}
+
+#[derive(Deserialize)]
+pub struct AlarmsQuery {
+    /// true时包括正常的告警
+    #[serde(default)]
+    all: bool,
+}
+
+#[get("/alarms")]
+pub async fn get_alarms(
+    query: web::Query<AlarmsQuery>,
+    identity: Identity,
+    alarms: web::Data<AlarmEngine>,
+) -> Result<HttpResponse, Error> {
+    identity.authorize(Role::Viewer, None)?;
+    let statuses: Vec<AlarmStatus> = alarms
+        .statuses(query.all)
+        .into_iter()
+        .filter(|status| identity.can_access(&status.device))
+        .collect();
+    Ok(HttpResponse::Ok().json(Response::success(statuses)))
+}
+
+#[post("/alarms/{id}/ack")]
+pub async fn ack_alarm(
+    id: web::Path<String>,
+    identity: Identity,
+    alarms: web::Data<AlarmEngine>,
+) -> Result<HttpResponse, Error> {
+    let id = id.into_inner();
+    let Some(device) = alarms.device_of(&id) else {
+        return Ok(
+            HttpResponse::NotFound().json(Response::error(AckError::UnknownAlarm.to_string()))
+        );
+    };
+    identity.authorize(Role::Operator, Some(device))?;
+    match alarms.acknowledge(&id, &identity.name) {
+        Ok(status) => Ok(HttpResponse::Ok().json(Response::success(status))),
+        Err(err @ AckError::UnknownAlarm) => {
+            Ok(HttpResponse::NotFound().json(Response::error(err.to_string())))
+        }
+        Err(err @ AckError::NotRaised) => {
+            Ok(HttpResponse::Conflict().json(Response::error(err.to_string())))
+        }
+    }
+}
+
+#[get("/alarms/journal")]
+pub async fn get_alarm_journal(
+    query: web::Query<AuditQuery>,
+    identity: Identity,
+    alarms: web::Data<AlarmEngine>,
+) -> Result<HttpResponse, Error> {
+    identity.authorize(Role::Viewer, None)?;
+    let events: Vec<AlarmEvent> = alarms.journal(query.limit.unwrap_or(100), |event| {
+        identity.can_access(&event.device)
+    });
+    Ok(HttpResponse::Ok().json(Response::success(events)))
+}
+
+/// Server-Sent Events，只推送调用方能访问的设备的事件
+#[get("/events")]
+pub async fn get_events(
+    identity: Identity,
+    events: web::Data<EventBus>,
+) -> Result<HttpResponse, Error> {
+    identity.authorize(Role::Viewer, None)?;
+    let stream = sse_stream(events.subscribe(), move |event| {
+        identity.can_access(event.device())
+    });
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .streaming(stream))
+}
@@ -0,0 +1,137 @@
+use std::{
+    collections::HashMap,
+    future,
+    sync::{Arc, Mutex},
+};
+
+use actix_web::rt::spawn;
+use config::{Config, File, FileFormat};
+use serde::de::DeserializeOwned;
+use tokio::net::TcpListener;
+use tokio_modbus::{
+    bytes::Bytes,
+    server::{tcp::Server, Service},
+    ExceptionCode, Request, Response, SlaveRequest,
+};
+
+use crate::{
+    app_config::{self, AuditConfig},
+    audit::{self, AuditLog},
+    device::{build_devices, Devices},
+};
+
+/// 模拟设备：没写过的保持寄存器的值等于地址，记录收到的unit id和写入
+#[derive(Clone, Default)]
+pub struct Simulator {
+    pub registers: Arc<Mutex<HashMap<u16, u16>>>,
+    pub unit_ids: Arc<Mutex<Vec<u8>>>,
+    pub writes: Arc<Mutex<Vec<Request<'static>>>>,
+}
+
+impl Service for Simulator {
+    type Request = SlaveRequest<'static>;
+    type Response = Response;
+    type Exception = ExceptionCode;
+    type Future = future::Ready<Result<Response, ExceptionCode>>;
+
+    fn call(&self, request: Self::Request) -> Self::Future {
+        self.unit_ids.lock().unwrap().push(request.slave);
+        let mut registers = self.registers.lock().unwrap();
+        let write = request.request.clone();
+        future::ready(match request.request {
+            Request::ReadHoldingRegisters(address, count) => Ok(Response::ReadHoldingRegisters(
+                // 读到0xFFFF时不能溢出
+                (address as u32..address as u32 + count as u32)
+                    .map(|address| {
+                        let address = address as u16;
+                        registers.get(&address).copied().unwrap_or(address)
+                    })
+                    .collect(),
+            )),
+            Request::WriteSingleRegister(address, value) => {
+                registers.insert(address, value);
+                self.writes.lock().unwrap().push(write);
+                Ok(Response::WriteSingleRegister(address, value))
+            }
+            Request::WriteMultipleRegisters(address, values) => {
+                for (register, value) in (address..=u16::MAX).zip(values.iter()) {
+                    registers.insert(register, *value);
+                }
+                self.writes.lock().unwrap().push(write);
+                Ok(Response::WriteMultipleRegisters(
+                    address,
+                    values.len() as u16,
+                ))
+            }
+            Request::WriteMultipleCoils(address, coils) => {
+                self.writes.lock().unwrap().push(write);
+                Ok(Response::WriteMultipleCoils(address, coils.len() as u16))
+            }
+            // 读设备标识，响应原样回显请求的数据
+            Request::Custom(0x2B, data) => {
+                Ok(Response::Custom(0x2B, Bytes::from(data.into_owned())))
+            }
+            _ => Err(ExceptionCode::IllegalFunction),
+        })
+    }
+}
+
+/// 在随机端口上启动模拟设备，返回地址
+pub async fn start_simulator(simulator: Simulator) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    spawn(async move {
+        let on_connected = |stream, _| {
+            let simulator = simulator.clone();
+            async move { Ok(Some((simulator, stream))) }
+        };
+        Server::new(listener).serve(&on_connected, |_| {}).await
+    });
+    address
+}
+
+/// 没人监听的地址，连接会被立即拒绝
+pub fn unused_address() -> String {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string()
+}
+
+/// 清空的临时目录，测试是并行跑的，名字要各不相同
+pub fn temp_dir(name: &str) -> String {
+    let dir = std::env::temp_dir().join(format!("modbus-test-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir.to_string_lossy().into_owned()
+}
+
+pub fn audit_log(name: &str) -> Arc<AuditLog> {
+    Arc::new(
+        audit::open(&AuditConfig {
+            directory: temp_dir(name),
+            file_name: "audit.jsonl".to_string(),
+            memory_entries: 10,
+        })
+        .unwrap(),
+    )
+}
+
+/// 用config.toml里的写法解析一段配置
+pub fn from_toml<T: DeserializeOwned>(toml: &str) -> T {
+    Config::builder()
+        .add_source(File::from_str(toml, FileFormat::Toml))
+        .build()
+        .unwrap()
+        .try_deserialize()
+        .unwrap()
+}
+
+/// 用config.toml里`[[modbus.configs]]`的写法解析一个设备
+pub fn modbus_config(toml: &str) -> app_config::Modbus {
+    from_toml(toml)
+}
+
+pub fn devices(toml: &str) -> Devices {
+    build_devices(&[modbus_config(toml)]).unwrap()
+}
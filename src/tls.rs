@@ -0,0 +1,141 @@
+use std::{
+    fs::{self, File},
+    io::BufReader,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use actix_web::rt::time::interval;
+use rustls::{
+    crypto::ring::{default_provider, sign::any_supported_type},
+    pki_types::{CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier},
+    sign::CertifiedKey,
+    RootCertStore, ServerConfig,
+};
+use tracing::{info, warn};
+
+use crate::app_config::TlsConfig;
+
+pub fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let file = File::open(path).map_err(|err| format!("打开{}失败：{}", path, err))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| format!("解析{}失败：{}", path, err))?;
+    if certs.is_empty() {
+        return Err(format!("{}里没有证书", path));
+    }
+    Ok(certs)
+}
+
+pub fn load_private_key(path: &str) -> Result<PrivateKeyDer<'static>, String> {
+    let file = File::open(path).map_err(|err| format!("打开{}失败：{}", path, err))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|err| format!("解析{}失败：{}", path, err))?
+        .ok_or_else(|| format!("{}里没有私钥", path))
+}
+
+pub fn load_roots(path: &str) -> Result<RootCertStore, String> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots
+            .add(cert)
+            .map_err(|err| format!("{}里的CA证书无效：{}", path, err))?;
+    }
+    Ok(roots)
+}
+
+fn load_certified_key(config: &TlsConfig) -> Result<CertifiedKey, String> {
+    let certs = load_certs(&config.cert_file)?;
+    let key = load_private_key(&config.key_file)?;
+    let signing_key = any_supported_type(&key)
+        .map_err(|err| format!("不支持{}的私钥类型：{}", config.key_file, err))?;
+    Ok(CertifiedKey::new(certs, signing_key))
+}
+
+fn modified(config: &TlsConfig) -> Option<SystemTime> {
+    [&config.cert_file, &config.key_file]
+        .iter()
+        .filter_map(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok())
+        .max()
+}
+
+/// 握手时返回当前的证书，证书文件更新后由`reload_loop`替换，不用重启服务
+#[derive(Debug)]
+pub struct CertReloader {
+    config: TlsConfig,
+    current: RwLock<Arc<CertifiedKey>>,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl ResolvesServerCert for CertReloader {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+impl CertReloader {
+    fn new(config: &TlsConfig) -> Result<Self, String> {
+        Ok(CertReloader {
+            config: config.clone(),
+            modified: Mutex::new(modified(config)),
+            current: RwLock::new(Arc::new(load_certified_key(config)?)),
+        })
+    }
+
+    fn reload_if_changed(&self) {
+        let modified = modified(&self.config);
+        let mut last = self.modified.lock().unwrap();
+        if modified == *last {
+            return;
+        }
+        // 证书和私钥可能不是同时写完的，加载失败时不更新时间，下次再试
+        match load_certified_key(&self.config) {
+            Ok(key) => {
+                *self.current.write().unwrap() = Arc::new(key);
+                *last = modified;
+                info!("重新加载TLS证书{}", self.config.cert_file);
+            }
+            Err(err) => warn!("重新加载TLS证书失败，继续使用旧证书：{}", err),
+        }
+    }
+}
+
+/// HTTPS的rustls配置。客户端CA只在启动时加载，修改后需要重启
+pub fn server_config(config: &TlsConfig) -> Result<(ServerConfig, Arc<CertReloader>), String> {
+    let provider = Arc::new(default_provider());
+    let verifier = match &config.client_ca_file {
+        Some(ca_file) => {
+            let builder = WebPkiClientVerifier::builder_with_provider(
+                Arc::new(load_roots(ca_file)?),
+                provider.clone(),
+            );
+            let builder = if config.client_auth_required {
+                builder
+            } else {
+                builder.allow_unauthenticated()
+            };
+            builder
+                .build()
+                .map_err(|err| format!("创建客户端证书校验失败：{}", err))?
+        }
+        None => WebPkiClientVerifier::no_client_auth(),
+    };
+    let reloader = Arc::new(CertReloader::new(config)?);
+    let server_config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|err| format!("创建TLS配置失败：{}", err))?
+        .with_client_cert_verifier(verifier)
+        .with_cert_resolver(reloader.clone());
+    Ok((server_config, reloader))
+}
+
+pub async fn reload_loop(reloader: Arc<CertReloader>) {
+    let mut ticker = interval(Duration::from_millis(
+        reloader.config.reload_interval_ms.max(1000),
+    ));
+    loop {
+        ticker.tick().await;
+        reloader.reload_if_changed();
+    }
+}
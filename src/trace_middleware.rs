@@ -1,91 +1,123 @@
-use std::future::{ready, Ready};
+use std::fmt;
 
 use actix_web::{
     body::MessageBody,
-    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    dev::{ServiceRequest, ServiceResponse},
+    http::{
+        header::{HeaderMap, HeaderName, HeaderValue},
+        StatusCode,
+    },
     middleware::Next,
-    Error,
+    Error, HttpResponse, ResponseError,
 };
-use futures_util::future::LocalBoxFuture;
-use opentelemetry::{
-    global,
-    trace::{Span, SpanKind, Status, Tracer},
-};
-use tracing::info;
-pub struct Trace;
+use opentelemetry::{global, propagation::Extractor, trace::TraceContextExt};
+use tracing::{debug, field, info_span, Instrument};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// 响应里带上trace id，方便根据失败的请求去查trace和日志
+pub const TRACE_ID_HEADER: &str = "x-trace-id";
 
-// Middleware factory is `Transform` trait
-// `S` - type of the next service
-// `B` - type of response's body
-impl<S, B> Transform<S, ServiceRequest> for Trace
-where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
-    S::Future: 'static,
-    B: 'static,
-{
-    type Response = ServiceResponse<B>;
-    type Error = Error;
-    type InitError = ();
-    type Transform = TraceMiddleware<S>;
-    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+struct HeaderExtractor<'a>(&'a HeaderMap);
 
-    fn new_transform(&self, service: S) -> Self::Future {
-        ready(Ok(TraceMiddleware { service }))
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
     }
 }
-pub struct TraceMiddleware<S> {
-    service: S,
-}
-impl<S, B> Service<ServiceRequest> for TraceMiddleware<S>
-where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
-    S::Future: 'static,
-    B: 'static,
-{
-    type Response = ServiceResponse<B>;
-    type Error = Error;
-    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
 
-    forward_ready!(service);
-
-    fn call(&self, req: ServiceRequest) -> Self::Future {
-        println!("Hi from start. You requested: {}", req.path());
+/// 内层返回的错误（比如认证失败），生成响应时也带上trace id
+#[derive(Debug)]
+struct TracedError {
+    inner: Error,
+    trace_id: String,
+}
 
-        let fut = self.service.call(req);
+impl fmt::Display for TracedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.inner, f)
+    }
+}
 
-        Box::pin(async move {
-            let res = fut.await?;
+impl ResponseError for TracedError {
+    fn status_code(&self) -> StatusCode {
+        self.inner.as_response_error().status_code()
+    }
 
-            println!("Hi from response");
-            Ok(res)
-        })
+    fn error_response(&self) -> HttpResponse {
+        let mut response = self.inner.error_response();
+        if let Ok(value) = HeaderValue::from_str(&self.trace_id) {
+            response
+                .headers_mut()
+                .insert(HeaderName::from_static(TRACE_ID_HEADER), value);
+        }
+        response
     }
 }
+
 pub async fn trace_middleware(
     req: ServiceRequest,
     next: Next<impl MessageBody>,
 ) -> Result<ServiceResponse<impl MessageBody>, Error> {
-    let tracer = global::tracer("dice_server");
-    let mut span = tracer
-        .span_builder(format!("{} {}", req.method(), req.uri().path()))
-        .with_kind(SpanKind::Server)
-        .start(&tracer);
-    let res = next.call(req).await;
+    // 路由模板，比如/modbus/{name}，没有匹配的路由时用原始路径
+    let route = req
+        .match_pattern()
+        .unwrap_or_else(|| req.path().to_string());
+    let span = info_span!(
+        "HTTP request",
+        otel.name = %format!("{} {}", req.method(), route),
+        otel.kind = "server",
+        http.request.method = %req.method(),
+        http.route = %route,
+        url.path = %req.path(),
+        client.address = req.connection_info().realip_remote_addr().map(str::to_string),
+        http.response.status_code = field::Empty,
+        otel.status_code = field::Empty,
+        trace_id = field::Empty,
+    );
+    // 从traceparent中取出调用方的上下文，没有时这里就是新trace的根span
+    let parent = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(req.headers()))
+    });
+    span.set_parent(parent);
+    let trace_id = format!("{:032x}", span.context().span().span_context().trace_id());
+    span.record("trace_id", &trace_id);
+
+    debug!(parent: &span, "收到请求：{} {}", req.method(), req.path());
+    let res = next.call(req).instrument(span.clone()).await;
     // post-processing
-    match &res {
-        Ok(ref response) => {
-            if response.status().is_server_error() || response.status().is_client_error() {
+    match res {
+        Ok(mut response) => {
+            let status = response.status();
+            span.record("http.response.status_code", status.as_u16());
+            if status.is_server_error() || status.is_client_error() {
                 // 如果是 4xx 或 5xx 错误，标记 span 为 Error
-                span.set_status(Status::error(response.status().to_string()));
+                span.record("otel.status_code", "ERROR");
             } else {
                 // 请求成功，设置状态为 Ok
-                span.set_status(Status::Ok);
+                span.record("otel.status_code", "OK");
+            }
+            if let Ok(value) = HeaderValue::from_str(&trace_id) {
+                response
+                    .headers_mut()
+                    .insert(HeaderName::from_static(TRACE_ID_HEADER), value);
             }
+            debug!(parent: &span, "请求完成：{}", status);
+            Ok(response)
         }
         Err(err) => {
             // 如果请求处理出错，设置状态为 Error
-            span.set_status(Status::error(err.to_string()));
+            let status = err.as_response_error().status_code();
+            span.record("http.response.status_code", status.as_u16());
+            span.record("otel.status_code", "ERROR");
+            debug!(parent: &span, "请求处理出错：{}", err);
+            Err(TracedError {
+                inner: err,
+                trace_id,
+            }
+            .into())
         }
     }
-    res
 }
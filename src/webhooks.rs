@@ -0,0 +1,480 @@
+use std::{
+    collections::VecDeque,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use actix_web::rt::time::sleep;
+use chrono::{DateTime, Local, TimeDelta};
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::{mpsc::UnboundedReceiver, Notify};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    app_config::{WebhookSinkConfig, WebhooksConfig},
+    device::valid_name,
+    events::Event,
+};
+
+/// 发件箱里的一条通知，入队时就按模板生成好请求体，一条一个文件
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Delivery {
+    id: String,
+    event: String,
+    created: DateTime<Local>,
+    attempts: u32,
+    next_attempt: DateTime<Local>,
+    last_error: Option<String>,
+    body: String,
+}
+
+/// 一个webhook目标，按入队顺序一条一条发送
+struct Sink {
+    config: WebhookSinkConfig,
+    method: Method,
+    client: reqwest::Client,
+    dir: PathBuf,
+    queue: Mutex<VecDeque<Delivery>>,
+    notify: Notify,
+}
+
+impl Sink {
+    fn path(&self, delivery: &Delivery) -> PathBuf {
+        self.dir.join(format!("{}.json", delivery.id))
+    }
+
+    /// 先写临时文件再改名，避免写了一半的文件
+    fn save(&self, delivery: &Delivery) -> io::Result<()> {
+        let path = self.path(delivery);
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(delivery).unwrap())?;
+        fs::rename(tmp, path)
+    }
+
+    fn accepts(&self, event: &Event) -> bool {
+        self.config.events.is_empty() || self.config.events.iter().any(|e| e == event.name())
+    }
+
+    fn enqueue(&self, delivery: Delivery) {
+        if let Err(err) = self.save(&delivery) {
+            // 写不进发件箱也照样发，只是重启后会丢
+            error!("webhook {}写入发件箱失败：{}", self.config.name, err);
+        }
+        self.queue.lock().unwrap().push_back(delivery);
+        self.notify.notify_one();
+    }
+
+    async fn send(&self, delivery: &Delivery) -> Result<(), String> {
+        let mut request = self
+            .client
+            .request(self.method.clone(), &self.config.url)
+            .timeout(Duration::from_millis(self.config.timeout_ms));
+        if !self
+            .config
+            .headers
+            .keys()
+            .any(|name| name.eq_ignore_ascii_case("content-type"))
+        {
+            request = request.header("content-type", "application/json");
+        }
+        for (name, value) in &self.config.headers {
+            request = request.header(name, value);
+        }
+        let response = request
+            .body(delivery.body.clone())
+            .send()
+            .await
+            .map_err(|err| err.to_string())?;
+        let status = response.status();
+        if status.is_success() {
+            Ok(())
+        } else {
+            Err(format!("HTTP {}", status))
+        }
+    }
+
+    /// 第n次失败后等多久再试
+    fn backoff(&self, attempts: u32) -> Duration {
+        let backoff = self
+            .config
+            .backoff_ms
+            .saturating_mul(1u64 << attempts.saturating_sub(1).min(32));
+        Duration::from_millis(backoff.min(self.config.max_backoff_ms))
+    }
+
+    /// 尝试次数用完，移到failed目录留着人工处理
+    fn give_up(&self, delivery: &Delivery) {
+        let failed = self.dir.join("failed");
+        let moved = fs::create_dir_all(&failed).and_then(|_| {
+            fs::rename(
+                self.path(delivery),
+                failed.join(format!("{}.json", delivery.id)),
+            )
+        });
+        if let Err(err) = moved {
+            error!(
+                "移动webhook {}的通知{}失败：{}",
+                self.config.name, delivery.id, err
+            );
+        }
+    }
+}
+
+/// 所有webhook目标，事件经过发件箱异步发送
+pub struct Webhooks {
+    sinks: Vec<Arc<Sink>>,
+    next_id: AtomicU64,
+}
+
+impl Webhooks {
+    pub fn new(config: &WebhooksConfig) -> Result<Self, String> {
+        let mut sinks: Vec<Arc<Sink>> = Vec::new();
+        for sink in &config.sinks {
+            if !valid_name(&sink.name) {
+                return Err(format!(
+                    "webhook名字{:?}不合法，只能包含字母、数字、_、-和.",
+                    sink.name
+                ));
+            }
+            if sinks.iter().any(|other| other.config.name == sink.name) {
+                return Err(format!("webhook {}重复", sink.name));
+            }
+            let method = Method::from_bytes(sink.method.to_uppercase().as_bytes())
+                .map_err(|_| format!("webhook {}的method {}不合法", sink.name, sink.method))?;
+            let dir = Path::new(&config.outbox_dir).join(&sink.name);
+            let queue = load_outbox(&dir)
+                .map_err(|err| format!("读取webhook {}的发件箱失败：{}", sink.name, err))?;
+            if !queue.is_empty() {
+                info!(
+                    "webhook {}的发件箱里有{}条待发送的通知",
+                    sink.name,
+                    queue.len()
+                );
+            }
+            sinks.push(Arc::new(Sink {
+                config: sink.clone(),
+                method,
+                client: reqwest::Client::new(),
+                dir,
+                queue: Mutex::new(queue),
+                notify: Notify::new(),
+            }));
+        }
+        Ok(Webhooks {
+            sinks,
+            next_id: AtomicU64::new(0),
+        })
+    }
+
+    fn enqueue(&self, event: &Event) {
+        let value = serde_json::to_value(event).unwrap();
+        for sink in self.sinks.iter().filter(|sink| sink.accepts(event)) {
+            let now = Local::now();
+            let body = match &sink.config.body {
+                Some(template) => render(template, &value),
+                None => value.to_string(),
+            };
+            sink.enqueue(Delivery {
+                // 文件名按时间排序，重启后按原来的顺序发
+                id: format!(
+                    "{:020}-{:06}",
+                    now.timestamp_micros(),
+                    self.next_id.fetch_add(1, Ordering::Relaxed) % 1_000_000
+                ),
+                event: event.name().to_string(),
+                created: now,
+                attempts: 0,
+                next_attempt: now,
+                last_error: None,
+                body,
+            });
+        }
+    }
+}
+
+fn load_outbox(dir: &Path) -> io::Result<VecDeque<Delivery>> {
+    fs::create_dir_all(dir)?;
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    paths.sort();
+    Ok(paths
+        .iter()
+        .filter_map(|path| {
+            let delivery = fs::read(path)
+                .ok()
+                .and_then(|bytes| serde_json::from_slice(&bytes).ok());
+            if delivery.is_none() {
+                warn!("跳过无法解析的发件箱文件{}", path.display());
+            }
+            delivery
+        })
+        .collect())
+}
+
+/// 替换模板里的`{{字段}}`；字符串按JSON转义但不加引号，`{{event}}`是整个事件的JSON
+fn render(template: &str, event: &Value) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let Some(end) = after.find("}}") else {
+            rest = &rest[start..];
+            break;
+        };
+        let key = after[..end].trim();
+        match (key, event.get(key)) {
+            ("event", _) => out.push_str(&event.to_string()),
+            (_, Some(Value::String(text))) => {
+                let quoted = Value::String(text.clone()).to_string();
+                out.push_str(&quoted[1..quoted.len() - 1]);
+            }
+            (_, None | Some(Value::Null)) => {}
+            (_, Some(other)) => out.push_str(&other.to_string()),
+        }
+        rest = &after[end + 2..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// 把事件放进各个webhook的发件箱，直到进程退出。
+/// `receiver`要在产生事件的任务启动之前用`EventBus::subscribe_lossless`订阅，否则启动时的事件会漏掉
+pub async fn dispatch_loop(webhooks: Arc<Webhooks>, mut receiver: UnboundedReceiver<Event>) {
+    while let Some(event) = receiver.recv().await {
+        webhooks.enqueue(&event);
+    }
+}
+
+/// 每个webhook启动一个发送任务，失败时按退避时间重试，直到进程退出
+pub fn spawn_delivery_loops(webhooks: &Webhooks) {
+    for sink in &webhooks.sinks {
+        actix_web::rt::spawn(delivery_loop(sink.clone()));
+    }
+}
+
+async fn delivery_loop(sink: Arc<Sink>) {
+    loop {
+        let Some(mut delivery) = sink.queue.lock().unwrap().front().cloned() else {
+            sink.notify.notified().await;
+            continue;
+        };
+        let wait = delivery.next_attempt - Local::now();
+        if wait > TimeDelta::zero() {
+            sleep(wait.to_std().unwrap_or_default()).await;
+        }
+        delivery.attempts += 1;
+        match sink.send(&delivery).await {
+            Ok(()) => {
+                debug!("webhook {}发送通知{}成功", sink.config.name, delivery.id);
+                if let Err(err) = fs::remove_file(sink.path(&delivery)) {
+                    if err.kind() != io::ErrorKind::NotFound {
+                        error!("删除发件箱文件{}失败：{}", delivery.id, err);
+                    }
+                }
+                sink.queue.lock().unwrap().pop_front();
+            }
+            Err(err) if delivery.attempts >= sink.config.max_attempts => {
+                error!(
+                    "webhook {}发送通知{}失败{}次，放弃：{}",
+                    sink.config.name, delivery.id, delivery.attempts, err
+                );
+                sink.give_up(&delivery);
+                sink.queue.lock().unwrap().pop_front();
+            }
+            Err(err) => {
+                let backoff = sink.backoff(delivery.attempts);
+                warn!(
+                    "webhook {}发送通知{}失败（第{}次）：{}，{}ms后重试",
+                    sink.config.name,
+                    delivery.id,
+                    delivery.attempts,
+                    err,
+                    backoff.as_millis()
+                );
+                delivery.last_error = Some(err);
+                delivery.next_attempt =
+                    Local::now() + TimeDelta::from_std(backoff).unwrap_or_default();
+                if let Err(err) = sink.save(&delivery) {
+                    error!("更新发件箱文件{}失败：{}", delivery.id, err);
+                }
+                if let Some(front) = sink.queue.lock().unwrap().front_mut() {
+                    *front = delivery;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Instant};
+
+    use actix_web::{rt::spawn, web, App, HttpResponse, HttpServer};
+
+    use super::*;
+    use crate::{
+        device_health::DeviceState,
+        events::{DeviceStateEvent, EventBus},
+        test_support::temp_dir,
+    };
+
+    /// 代替webhook接收方的HTTP服务，前`fail_first`个请求返回503
+    struct StandIn {
+        fail_first: usize,
+        requests: Mutex<Vec<(Instant, String)>>,
+    }
+
+    async fn receive(state: web::Data<StandIn>, body: String) -> HttpResponse {
+        let mut requests = state.requests.lock().unwrap();
+        requests.push((Instant::now(), body));
+        if requests.len() <= state.fail_first {
+            HttpResponse::ServiceUnavailable().finish()
+        } else {
+            HttpResponse::Ok().finish()
+        }
+    }
+
+    fn stand_in(fail_first: usize) -> (web::Data<StandIn>, String) {
+        let state = web::Data::new(StandIn {
+            fail_first,
+            requests: Mutex::new(Vec::new()),
+        });
+        let data = state.clone();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(data.clone())
+                .route("/hook", web::post().to(receive))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let url = format!("http://{}/hook", server.addrs()[0]);
+        spawn(server.run());
+        (state, url)
+    }
+
+    async fn wait_for_requests(state: &StandIn, n: usize) -> Vec<(Instant, String)> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let requests = state.requests.lock().unwrap().clone();
+            if requests.len() >= n {
+                return requests;
+            }
+            assert!(Instant::now() < deadline, "只收到{}个请求", requests.len());
+            sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    async fn wait_for_empty_outbox(dir: &Path) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !load_outbox(dir).unwrap().is_empty() {
+            assert!(Instant::now() < deadline, "发件箱没有清空");
+            sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    fn config(test: &str, url: &str) -> WebhooksConfig {
+        WebhooksConfig {
+            outbox_dir: temp_dir(&format!("webhooks-{}", test)),
+            sinks: vec![WebhookSinkConfig {
+                name: "test".to_string(),
+                url: url.to_string(),
+                method: "POST".to_string(),
+                headers: HashMap::new(),
+                body: Some(r#"{"device": "{{device}}", "to": "{{to}}"}"#.to_string()),
+                events: Vec::new(),
+                timeout_ms: 1000,
+                max_attempts: 5,
+                backoff_ms: 100,
+                max_backoff_ms: 1000,
+            }],
+        }
+    }
+
+    fn state_change(from: DeviceState, to: DeviceState) -> Event {
+        Event::DeviceState(DeviceStateEvent {
+            timestamp: Local::now(),
+            device: "main".to_string(),
+            address: "127.0.0.1:502".to_string(),
+            from,
+            to,
+            last_error: None,
+        })
+    }
+
+    #[actix_web::test]
+    async fn failed_deliveries_are_retried_with_backoff() {
+        let (state, url) = stand_in(2);
+        let config = config("retry", &url);
+        let webhooks = Webhooks::new(&config).unwrap();
+        spawn_delivery_loops(&webhooks);
+        webhooks.enqueue(&state_change(DeviceState::Up, DeviceState::Down));
+        let requests = wait_for_requests(&state, 3).await;
+        assert!(requests
+            .iter()
+            .all(|(_, body)| body == r#"{"device": "main", "to": "down"}"#));
+        // 第一次失败后等backoff_ms，之后翻倍
+        assert!(requests[1].0 - requests[0].0 >= Duration::from_millis(100));
+        assert!(requests[2].0 - requests[1].0 >= Duration::from_millis(200));
+        wait_for_empty_outbox(&webhooks.sinks[0].dir).await;
+        sleep(Duration::from_millis(300)).await;
+        assert_eq!(state.requests.lock().unwrap().len(), 3);
+        let _ = fs::remove_dir_all(&config.outbox_dir);
+    }
+
+    #[actix_web::test]
+    async fn outbox_is_replayed_after_restart() {
+        let (state, url) = stand_in(0);
+        let config = config("replay", &url);
+        // 入队之后、发送之前进程退出
+        let before = Webhooks::new(&config).unwrap();
+        before.enqueue(&state_change(DeviceState::Up, DeviceState::Down));
+        before.enqueue(&state_change(DeviceState::Down, DeviceState::Up));
+        let dir = before.sinks[0].dir.clone();
+        drop(before);
+        assert_eq!(load_outbox(&dir).unwrap().len(), 2);
+
+        let after = Webhooks::new(&config).unwrap();
+        spawn_delivery_loops(&after);
+        let requests = wait_for_requests(&state, 2).await;
+        let bodies: Vec<&str> = requests.iter().map(|(_, body)| body.as_str()).collect();
+        assert_eq!(
+            bodies,
+            [
+                r#"{"device": "main", "to": "down"}"#,
+                r#"{"device": "main", "to": "up"}"#
+            ]
+        );
+        wait_for_empty_outbox(&dir).await;
+        let _ = fs::remove_dir_all(&config.outbox_dir);
+    }
+
+    #[actix_web::test]
+    async fn events_are_not_lost_when_dispatch_falls_behind() {
+        let config = config("lossless", "http://127.0.0.1:9/hook");
+        let webhooks = Arc::new(Webhooks::new(&config).unwrap());
+        let events = EventBus::default();
+        let receiver = events.subscribe_lossless();
+        // 比广播通道的容量多，分发任务还没开始读
+        for _ in 0..1500 {
+            events.publish(state_change(DeviceState::Up, DeviceState::Down));
+        }
+        spawn(dispatch_loop(webhooks.clone(), receiver));
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while webhooks.sinks[0].queue.lock().unwrap().len() < 1500 {
+            assert!(Instant::now() < deadline);
+            sleep(Duration::from_millis(10)).await;
+        }
+        let _ = fs::remove_dir_all(&config.outbox_dir);
+    }
+}
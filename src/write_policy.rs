@@ -0,0 +1,188 @@
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::device::{Device, Turn};
+
+/// 写入被策略拒绝的原因，原样返回给调用方并记入审计日志
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "rule", rename_all = "snake_case")]
+pub enum PolicyViolation {
+    ReadOnly,
+    /// 写入的最后一个寄存器超过了0xFFFF
+    AddressOverflow {
+        address: u16,
+        count: usize,
+    },
+    AddressNotAllowed {
+        address: u16,
+    },
+    OutOfRange {
+        address: u16,
+        value: u16,
+        min: Option<u16>,
+        max: Option<u16>,
+    },
+    Interlock {
+        address: u16,
+        require_register: u16,
+        require_value: u16,
+        /// 读到的联锁寄存器的值，读失败时为None
+        actual: Option<u16>,
+        description: String,
+    },
+    /// 掩码写的结果取决于设备上的当前值，有取值范围限制的寄存器不允许掩码写
+    MaskWriteLimited {
+        address: u16,
+    },
+}
+
+impl fmt::Display for PolicyViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyViolation::ReadOnly => write!(f, "设备是只读的"),
+            PolicyViolation::AddressOverflow { address, count } => {
+                write!(f, "从地址{}写{}个寄存器超出了地址范围", address, count)
+            }
+            PolicyViolation::AddressNotAllowed { address } => {
+                write!(f, "地址{}不允许写入", address)
+            }
+            PolicyViolation::OutOfRange {
+                address,
+                value,
+                min,
+                max,
+            } => write!(
+                f,
+                "地址{}的值{}超出范围[{}, {}]",
+                address,
+                value,
+                min.map_or("-".to_string(), |min| min.to_string()),
+                max.map_or("-".to_string(), |max| max.to_string()),
+            ),
+            PolicyViolation::Interlock {
+                address,
+                require_register,
+                require_value,
+                actual: Some(actual),
+                ..
+            } => write!(
+                f,
+                "联锁：寄存器{}为{}，不等于{}，不允许写地址{}",
+                require_register, actual, require_value, address
+            ),
+            PolicyViolation::Interlock {
+                address,
+                require_register,
+                actual: None,
+                ..
+            } => write!(
+                f,
+                "联锁：读取寄存器{}失败，不允许写地址{}",
+                require_register, address
+            ),
+            PolicyViolation::MaskWriteLimited { address } => {
+                write!(f, "地址{}有取值范围限制，不允许掩码写", address)
+            }
+        }
+    }
+}
+
+/// 写之前按设备的写入策略检查只读、允许地址和取值范围，不需要读设备
+pub fn check(device: &Device, address: u16, values: &[u16]) -> Result<(), PolicyViolation> {
+    let policy = &device.write_policy;
+    if policy.read_only {
+        return Err(PolicyViolation::ReadOnly);
+    }
+    if address as usize + values.len() > 0x10000 {
+        return Err(PolicyViolation::AddressOverflow {
+            address,
+            count: values.len(),
+        });
+    }
+    for (address, value) in (address..=u16::MAX).zip(values.iter().copied()) {
+        if !policy.allowed.is_empty() && !policy.allowed.iter().any(|r| r.contains(address)) {
+            return Err(PolicyViolation::AddressNotAllowed { address });
+        }
+        for limit in policy
+            .limits
+            .iter()
+            .filter(|limit| limit.address == address)
+        {
+            if limit.min.is_some_and(|min| value < min) || limit.max.is_some_and(|max| value > max)
+            {
+                return Err(PolicyViolation::OutOfRange {
+                    address,
+                    value,
+                    min: limit.min,
+                    max: limit.max,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// 写线圈（功能码5、15）检查只读和允许地址，允许地址和寄存器共用一套，取值范围只对寄存器
+pub fn check_coils(device: &Device, address: u16, count: usize) -> Result<(), PolicyViolation> {
+    let policy = &device.write_policy;
+    if policy.read_only {
+        return Err(PolicyViolation::ReadOnly);
+    }
+    if address as usize + count > 0x10000 {
+        return Err(PolicyViolation::AddressOverflow { address, count });
+    }
+    for address in (address..=u16::MAX).take(count) {
+        if !policy.allowed.is_empty() && !policy.allowed.iter().any(|r| r.contains(address)) {
+            return Err(PolicyViolation::AddressNotAllowed { address });
+        }
+    }
+    Ok(())
+}
+
+/// 掩码写（功能码22）只检查只读和允许地址，写入的值要读设备才知道
+pub fn check_mask_write(device: &Device, address: u16) -> Result<(), PolicyViolation> {
+    let policy = &device.write_policy;
+    if policy.read_only {
+        return Err(PolicyViolation::ReadOnly);
+    }
+    if !policy.allowed.is_empty() && !policy.allowed.iter().any(|r| r.contains(address)) {
+        return Err(PolicyViolation::AddressNotAllowed { address });
+    }
+    if policy.limits.iter().any(|limit| limit.address == address) {
+        return Err(PolicyViolation::MaskWriteLimited { address });
+    }
+    Ok(())
+}
+
+/// 检查联锁条件，需要读设备；和随后的写入在同一个限速许可里，中间联锁寄存器不会被别的请求改掉
+pub async fn check_interlocks(
+    turn: &Turn<'_>,
+    address: u16,
+    count: usize,
+) -> Result<(), PolicyViolation> {
+    let policy = &turn.device().write_policy;
+    for address in (address..=u16::MAX).take(count) {
+        for interlock in policy
+            .interlocks
+            .iter()
+            .filter(|interlock| interlock.register == address)
+        {
+            let actual = turn
+                .read_holding_registers(interlock.require_register, 1)
+                .await
+                .ok()
+                .and_then(|values| values.first().copied());
+            if actual != Some(interlock.require_value) {
+                return Err(PolicyViolation::Interlock {
+                    address,
+                    require_register: interlock.require_register,
+                    require_value: interlock.require_value,
+                    actual,
+                    description: interlock.description.clone(),
+                });
+            }
+        }
+    }
+    Ok(())
+}